@@ -0,0 +1,53 @@
+use std::process::ExitCode;
+
+use clap::Parser;
+
+use bucket_jai::breaker::CircuitBreaker;
+use bucket_jai::config::{ConfigArgs, Configuration};
+use bucket_jai::migrate::{run, Options};
+use bucket_jai::redis::{redis_client, redis_pool, KeySpace, DEFAULT_NAMESPACE};
+
+/// Rewrites the Redis key layout to the schema version of this build
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    /// Report keys that would change without writing
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Keys per SCAN batch
+    #[arg(long, default_value_t = 500, value_parser = clap::value_parser!(u64).range(1..))]
+    batch: u64
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let config = match Configuration::load(&cli.config) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("configuration error: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or(&config.logging.level));
+
+    let opts = Options { dry_run: cli.dry_run, batch: cli.batch as usize };
+    let keys = KeySpace { prefix: config.redis.key_prefix.to_owned(), namespace: DEFAULT_NAMESPACE.to_string() };
+
+    // a one-off run reports the failure itself rather than failing fast
+    let breaker = CircuitBreaker::new("migrate", 0, config.redis.breaker_cooldown());
+    let result = redis_client(&config.redis).and_then(|client| run(&redis_pool(client, 1, config.redis.pool_timeout(), breaker), &keys, &opts));
+    match result {
+        Ok(version) => {
+            log::info!("Schema at version {}", version);
+            ExitCode::SUCCESS
+        },
+        Err(e) => {
+            log::error!("Migration failed, rerun to resume: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
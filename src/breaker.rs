@@ -0,0 +1,166 @@
+use std::{
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::metrics::{REDIS_BREAKER_STATE, REDIS_BREAKER_TRANSITIONS};
+
+/// State of a breaker, its discriminant being the value of the state gauge
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BreakerState { Closed = 0, Open = 1, HalfOpen = 2 }
+
+impl fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half-open"
+        })
+    }
+}
+
+struct Inner {
+    state: BreakerState,
+    failures: u32,
+    opened_at: Instant,
+    probing: bool
+}
+
+/// Fails Redis calls fast once `threshold` calls in a row found Redis unavailable.
+/// After `cooldown` a single probe goes through, closing the breaker when it succeeds and reopening it otherwise.
+pub struct CircuitBreaker {
+    name: String,
+    threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<Inner>
+}
+
+impl CircuitBreaker {
+    /// Breaker reported under `name` in logs and metrics, a `threshold` of 0 never opening
+    pub fn new(name: &str, threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        REDIS_BREAKER_STATE.with_label_values(&[name]).set(0);
+        CircuitBreaker {
+            name: name.to_string(),
+            threshold,
+            cooldown,
+            inner: Mutex::new(Inner { state: BreakerState::Closed, failures: 0, opened_at: Instant::now(), probing: false })
+        }
+    }
+
+    // callers only hold the lock for a few instructions, a poisoned one still has a consistent state
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn transition(&self, inner: &mut Inner, state: BreakerState) {
+        if inner.state == state { return }
+        match state {
+            BreakerState::Open => log::warn!("Redis {} circuit breaker open after {} failures, failing fast for {:?}", self.name, inner.failures, self.cooldown),
+            BreakerState::HalfOpen => log::info!("Redis {} circuit breaker half-open, probing", self.name),
+            BreakerState::Closed => log::info!("Redis {} circuit breaker closed", self.name)
+        }
+        inner.state = state;
+        REDIS_BREAKER_STATE.with_label_values(&[&self.name]).set(state as i64);
+        REDIS_BREAKER_TRANSITIONS.with_label_values(&[&self.name, &state.to_string()]).inc();
+    }
+
+    /// Whether a call may go through, letting one probe through once the cooldown elapsed
+    pub fn allow(&self) -> bool {
+        let mut inner = self.lock();
+        match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::Open if inner.opened_at.elapsed() >= self.cooldown => {
+                self.transition(&mut inner, BreakerState::HalfOpen);
+                inner.probing = true;
+                true
+            },
+            BreakerState::Open => false,
+            BreakerState::HalfOpen if inner.probing => false,
+            BreakerState::HalfOpen => {
+                inner.probing = true;
+                true
+            }
+        }
+    }
+
+    /// Records a call Redis answered, even with an error reply
+    pub fn success(&self) {
+        let mut inner = self.lock();
+        inner.failures = 0;
+        inner.probing = false;
+        self.transition(&mut inner, BreakerState::Closed);
+    }
+
+    /// Records a call failing because Redis was unavailable
+    pub fn failure(&self) {
+        if self.threshold == 0 { return }
+        let mut inner = self.lock();
+        inner.failures = inner.failures.saturating_add(1);
+        inner.probing = false;
+        if inner.state == BreakerState::HalfOpen || inner.failures >= self.threshold {
+            inner.opened_at = Instant::now();
+            self.transition(&mut inner, BreakerState::Open);
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.lock().state
+    }
+
+    /// Time until the next probe may go through, zero unless open
+    pub fn retry_after(&self) -> Duration {
+        let inner = self.lock();
+        match inner.state {
+            BreakerState::Open => self.cooldown.saturating_sub(inner.opened_at.elapsed()),
+            _ => Duration::ZERO
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_threshold_failures_in_a_row() {
+        let breaker = CircuitBreaker::new("test-open", 3, Duration::from_secs(60));
+        breaker.failure();
+        breaker.failure();
+        breaker.success();
+        breaker.failure();
+        breaker.failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow());
+        breaker.failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(!breaker.allow());
+        assert!(breaker.retry_after() > Duration::ZERO);
+    }
+
+    #[test]
+    fn single_probe_after_cooldown() {
+        let breaker = CircuitBreaker::new("test-probe", 1, Duration::ZERO);
+        breaker.failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(!breaker.allow());
+        // a failed probe reopens, a successful one closes
+        breaker.failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(breaker.allow());
+        breaker.success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow() && breaker.allow());
+        assert_eq!(breaker.retry_after(), Duration::ZERO);
+    }
+
+    #[test]
+    fn zero_threshold_never_opens() {
+        let breaker = CircuitBreaker::new("test-off", 0, Duration::from_secs(60));
+        for _ in 0..10 { breaker.failure(); }
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow());
+    }
+}
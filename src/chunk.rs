@@ -0,0 +1,227 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::SystemTime,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::hash::{get_id_from_context, get_id_from_hasher};
+use crate::redis::*;
+
+/// Layout of a chunked payload, stored in its bucket hash in place of `HASH_KF_DATA`
+pub struct Manifest {
+    pub tag: String,
+    pub chunks: usize,
+    pub chunk_bytes: usize,
+    pub size: usize
+}
+
+/// Writes a payload chunk by chunk under `tag` as its bytes arrive, hashing them on the way
+/// so the id is known once finished without holding more than one chunk in memory
+pub struct ChunkWriter {
+    client: RedisPool,
+    keys: KeySpace,
+    tag: String,
+    chunk_bytes: usize,
+    buffer: Vec<u8>,
+    chunks: usize,
+    size: usize,
+    hasher: Sha256
+}
+
+impl ChunkWriter {
+    pub fn new(client: &RedisPool, keys: &KeySpace, tag: &str, chunk_bytes: usize) -> ChunkWriter {
+        ChunkWriter {
+            client: client.clone(),
+            keys: keys.clone(),
+            tag: tag.to_string(),
+            chunk_bytes,
+            buffer: Vec::with_capacity(chunk_bytes),
+            chunks: 0,
+            size: 0,
+            hasher: Sha256::new()
+        }
+    }
+
+    pub fn write(&mut self, mut bytes: &[u8]) -> redis::RedisResult<()> {
+        self.hasher.update(bytes);
+        self.size += bytes.len();
+        while !bytes.is_empty() {
+            let take = (self.chunk_bytes - self.buffer.len()).min(bytes.len());
+            self.buffer.extend_from_slice(&bytes[..take]);
+            bytes = &bytes[take..];
+            if self.buffer.len() == self.chunk_bytes { self.flush()?; }
+        }
+        Ok(())
+    }
+
+    pub fn size(&self) -> usize { self.size }
+
+    fn flush(&mut self) -> redis::RedisResult<()> {
+        // in flight until its manifest is committed, so repairConsistency leaves the chunks alone
+        set_sorted_kvs(&self.client, &zset_k_uploads(&self.keys), &self.tag, now_millis() as isize)?;
+        set_bytes(&self.client, &str_k_chunk(&self.keys, &self.tag, self.chunks), &self.buffer)?;
+        self.chunks += 1;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Writes the last partial chunk, returning the id of the content and its manifest
+    pub fn finish(mut self) -> redis::RedisResult<(String, Manifest)> {
+        if !self.buffer.is_empty() { self.flush()?; }
+        let manifest = Manifest { tag: self.tag, chunks: self.chunks, chunk_bytes: self.chunk_bytes, size: self.size };
+        Ok((get_id_from_hasher(self.hasher), manifest))
+    }
+
+    /// Drops the chunks written so far, for an upload that failed midway
+    pub fn abort(self) {
+        let manifest = Manifest { tag: self.tag, chunks: self.chunks, chunk_bytes: self.chunk_bytes, size: self.size };
+        if let Err(e) = del_chunks(&self.client, &self.keys, &manifest) {
+            log::error!("Cannot discard chunks tagged {}, left for repairConsistency: {}", manifest.tag, e);
+        }
+    }
+}
+
+static UPLOADS : AtomicU64 = AtomicU64::new(0);
+
+/// Unix time in milliseconds
+pub fn now_millis() -> u128 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|x| x.as_millis()).unwrap_or(0)
+}
+
+/// Tag for the chunks of a streamed upload, whose id is only known once all of it is read
+pub fn upload_tag() -> String {
+    let _nanos = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|x| x.as_nanos()).unwrap_or(0);
+    let _seed = format!("{}:{}:{}", std::process::id(), _nanos, UPLOADS.fetch_add(1, Ordering::Relaxed));
+    format!("upload-{}", &get_id_from_context(&_seed)[..32])
+}
+
+/// Reads the chunks of a manifest one at a time, each being a separate Redis call
+pub struct ChunkReader {
+    client: RedisPool,
+    keys: KeySpace,
+    manifest: Manifest,
+    next: usize,
+    start: usize,
+    end: usize
+}
+
+impl ChunkReader {
+    pub fn new(client: &RedisPool, keys: &KeySpace, manifest: Manifest) -> ChunkReader {
+        let end = manifest.size;
+        ChunkReader { client: client.clone(), keys: keys.clone(), manifest, next: 0, start: 0, end }
+    }
+
+    /// Narrows the reader to bytes `start..end`, skipping the chunks before
+    /// and reading partly covered chunks with `GETRANGE`
+    pub fn range(mut self, start: usize, end: usize) -> ChunkReader {
+        self.end = end.min(self.manifest.size);
+        self.start = start.min(self.end);
+        self.next = self.start / self.manifest.chunk_bytes.max(1);
+        self
+    }
+
+    pub fn len(&self) -> usize { self.end - self.start }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+// bounds `lo..hi` within chunk `n` of the wanted bytes `start..end` of a payload, and whether they cover the whole chunk,
+// `None` past the last chunk holding any of them
+fn chunk_span(manifest: &Manifest, start: usize, end: usize, n: usize) -> Option<(usize, usize, bool)> {
+    let chunk_bytes = manifest.chunk_bytes.max(1);
+    let offset = n * chunk_bytes;
+    if n >= manifest.chunks || offset >= end || start >= end { return None }
+    let lo = start.saturating_sub(offset);
+    let hi = (end - offset).min(chunk_bytes);
+    Some((lo, hi, lo == 0 && offset + hi >= manifest.size.min(offset + chunk_bytes)))
+}
+
+impl Iterator for ChunkReader {
+    type Item = redis::RedisResult<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (lo, hi, whole) = chunk_span(&self.manifest, self.start, self.end, self.next)?;
+        let key = str_k_chunk(&self.keys, &self.manifest.tag, self.next);
+        self.next += 1;
+
+        let value = if whole { get_bytes(&self.client, &key) } else { get_range_bytes(&self.client, &key, lo, hi - 1) };
+        Some(match value {
+            Ok(Some(x)) => Ok(x),
+            Ok(None) => Err(redis::RedisError::from((redis::ErrorKind::ResponseError, "missing chunk", key))),
+            Err(e) => Err(e)
+        })
+    }
+}
+
+fn get_hash_usize(client: &RedisPool, key: &String, field: &str) -> redis::RedisResult<usize> {
+    match get_hash_kfv(client, key, &field.to_string())? {
+        Some(RedisRtn::Payload(x)) => Ok(x.parse::<usize>().unwrap_or(0)),
+        _ => Ok(0)
+    }
+}
+
+/// Manifest of bucket `id`, `None` when its payload is stored inline
+pub fn get_manifest(client: &RedisPool, keys: &KeySpace, id: &String) -> redis::RedisResult<Option<Manifest>> {
+    let key = hash_k_id(keys, id);
+    let tag = match get_hash_kfv(client, &key, &HASH_KF_CHUNK_TAG.to_string())? {
+        Some(RedisRtn::Payload(x)) => x,
+        _ => return Ok(None)
+    };
+    Ok(Some(Manifest {
+        tag,
+        chunks: get_hash_usize(client, &key, HASH_KF_CHUNKS)?,
+        chunk_bytes: get_hash_usize(client, &key, HASH_KF_CHUNK_BYTES)?,
+        size: get_hash_usize(client, &key, HASH_KF_SIZE)?
+    }))
+}
+
+/// Records `manifest` in the hash of bucket `id`, the tag last so readers never find it before the chunk count.
+/// The chunks are then no longer in flight.
+pub fn set_manifest(client: &RedisPool, keys: &KeySpace, id: &String, manifest: &Manifest) -> redis::RedisResult<()> {
+    let key = hash_k_id(keys, id);
+    set_hash_kfv(client, &key, &HASH_KF_CHUNKS.to_string(), &manifest.chunks.to_string())?;
+    set_hash_kfv(client, &key, &HASH_KF_CHUNK_BYTES.to_string(), &manifest.chunk_bytes.to_string())?;
+    set_hash_kfv(client, &key, &HASH_KF_CHUNK_TAG.to_string(), &manifest.tag)?;
+    del_sorted_kvs(client, &zset_k_uploads(keys), &manifest.tag)?;
+    Ok(())
+}
+
+pub fn del_chunks(client: &RedisPool, keys: &KeySpace, manifest: &Manifest) -> redis::RedisResult<()> {
+    for n in 0..manifest.chunks {
+        del_kv(client, &str_k_chunk(keys, &manifest.tag, n))?;
+    }
+    del_sorted_kvs(client, &zset_k_uploads(keys), &manifest.tag)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 10 bytes in chunks of 4, 4 and 2
+    fn manifest() -> Manifest {
+        Manifest { tag: "t".to_string(), chunks: 3, chunk_bytes: 4, size: 10 }
+    }
+
+    // spans read from the first chunk holding `start`, as `ChunkReader::range` does
+    fn spans(start: usize, end: usize) -> Vec<(usize, usize, bool)> {
+        (start / 4..).map_while(|n| chunk_span(&manifest(), start, end, n)).collect()
+    }
+
+    #[test]
+    fn whole_payload_reads_whole_chunks() {
+        assert_eq!(spans(0, 10), [(0, 4, true), (0, 4, true), (0, 2, true)]);
+    }
+
+    #[test]
+    fn ranges_read_covered_chunks_only() {
+        assert_eq!(spans(3, 9), [(3, 4, false), (0, 4, true), (0, 1, false)]);
+        assert_eq!(spans(4, 8), [(0, 4, true)]);
+        assert_eq!(spans(9, 10), [(1, 2, false)]);
+        // the short last chunk is whole once read from its start
+        assert_eq!(spans(8, 10), [(0, 2, true)]);
+        assert!(spans(5, 5).is_empty());
+        assert!(spans(10, 10).is_empty());
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    str::FromStr,
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    None, // stored as sent
+    Gzip,
+    Zstd
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Codec::None),
+            "gzip" => Ok(Codec::Gzip),
+            "zstd" => Ok(Codec::Zstd),
+            _ => Err(format!("unknown codec {}, expected none, gzip or zstd", s))
+        }
+    }
+}
+
+/// Name of the codec, also its `Content-Encoding` token
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Codec::None => "identity",
+            Codec::Gzip => "gzip",
+            Codec::Zstd => "zstd"
+        })
+    }
+}
+
+impl Codec {
+    /// Codec recorded for a bucket, `None` when its payload is stored as sent
+    pub fn from_name(name: &str) -> Option<Codec> {
+        match name {
+            "gzip" => Some(Codec::Gzip),
+            "zstd" => Some(Codec::Zstd),
+            _ => None
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            },
+            Codec::Zstd => zstd::encode_all(data, 0)
+        }
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Gzip => {
+                let mut decoded = Vec::new();
+                GzDecoder::new(data).read_to_end(&mut decoded)?;
+                Ok(decoded)
+            },
+            Codec::Zstd => zstd::decode_all(data)
+        }
+    }
+}
+
+/// Compressed form of `data` when it is at least `min_bytes` long and compressing it saves space
+pub fn compress_if_smaller(codec: Codec, min_bytes: usize, data: &[u8]) -> Option<Vec<u8>> {
+    if codec == Codec::None || data.len() < min_bytes { return None }
+    match codec.compress(data) {
+        Ok(x) if x.len() < data.len() => Some(x),
+        Ok(_) => None,
+        Err(e) => {
+            log::warn!("Cannot compress with {}, storing as sent: {}", codec, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codecs_round_trip() {
+        let data = "bucket ".repeat(500).into_bytes();
+        for codec in [Codec::None, Codec::Gzip, Codec::Zstd] {
+            let compressed = codec.compress(&data).unwrap();
+            assert_eq!(codec.decompress(&compressed).unwrap(), data);
+        }
+        assert!(Codec::Gzip.decompress(b"not gzip").is_err());
+    }
+
+    #[test]
+    fn stored_names_round_trip() {
+        for codec in [Codec::Gzip, Codec::Zstd] {
+            assert_eq!(Codec::from_name(&codec.to_string()), Some(codec));
+            assert_eq!(codec.to_string().parse::<Codec>(), Ok(codec));
+        }
+        // inline data stored as sent records `identity`, read back as no codec
+        assert_eq!(Codec::from_name(&Codec::None.to_string()), None);
+    }
+
+    #[test]
+    fn compressed_only_when_smaller() {
+        let data = "bucket ".repeat(500).into_bytes();
+        assert!(compress_if_smaller(Codec::Zstd, 1024, &data).is_some_and(|x| x.len() < data.len()));
+        assert_eq!(compress_if_smaller(Codec::Zstd, data.len() + 1, &data), None);
+        assert_eq!(compress_if_smaller(Codec::None, 0, &data), None);
+        // random-looking bytes do not shrink
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let noise : Vec<u8> = (0..2048).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 24) as u8
+        }).collect();
+        assert_eq!(compress_if_smaller(Codec::Gzip, 0, &noise), None);
+    }
+}
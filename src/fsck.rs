@@ -0,0 +1,344 @@
+use std::{collections::HashSet, time::Duration};
+
+use redis::Commands;
+
+use crate::chunk::now_millis;
+use crate::hash::{get_slang_from_id, is_id, SlangConfig};
+use crate::redis::*;
+
+pub const DANGLING_SLANG : &str = "DANGLING_SLANG";         // slang -> id without hash
+pub const UNINDEXED_SLANG : &str = "UNINDEXED_SLANG";       // slang -> id missing from the id zset
+pub const ORPHAN_ZSET : &str = "ORPHAN_ZSET";               // id zset without hash
+pub const STALE_ZSET_MEMBER : &str = "STALE_ZSET_MEMBER";   // zset member whose slang points elsewhere
+pub const HASH_WITHOUT_SLANG : &str = "HASH_WITHOUT_SLANG"; // hash unreachable by any slang
+pub const ORPHAN_HASH : &str = "ORPHAN_HASH";               // hash without mime nor content, as late writes to a deleted bucket leave
+pub const ORPHAN_CHUNK : &str = "ORPHAN_CHUNK";             // chunk no manifest refers to, nor written lately
+pub const STALE_UPLOAD : &str = "STALE_UPLOAD";             // in-flight chunk tag without a chunk written lately
+pub const ORPHAN_LABELS : &str = "ORPHAN_LABELS";           // labels hash without hash
+pub const STALE_LABEL : &str = "STALE_LABEL";               // label set member whose labels hash holds another value
+pub const ORPHAN_TERMS : &str = "ORPHAN_TERMS";             // search terms of a bucket without hash
+pub const STALE_TERM : &str = "STALE_TERM";                 // term set member not indexed with the term
+
+/// Time since their last chunk after which chunks without manifest are no longer taken as in flight
+pub const UPLOAD_GRACE : Duration = Duration::from_secs(15 * 60);
+
+const SAMPLES : usize = 10;
+const BATCH : usize = 500;
+
+pub struct Issue {
+    pub category: &'static str,
+    pub count: usize,
+    pub samples: Vec<String>
+}
+
+#[derive(Default)]
+pub struct Report {
+    pub scanned: usize,
+    pub repaired: usize,
+    pub issues: Vec<Issue>
+}
+
+impl Report {
+    fn record(&mut self, category: &'static str, key: &str) {
+        log::warn!("Inconsistency {} ([key] {})", category, key);
+        let index = match self.issues.iter().position(|x| x.category == category) {
+            Some(i) => i,
+            None => {
+                self.issues.push(Issue { category, count: 0, samples: Vec::new() });
+                self.issues.len() - 1
+            }
+        };
+        let issue = &mut self.issues[index];
+        issue.count += 1;
+        if issue.samples.len() < SAMPLES { issue.samples.push(key.to_string()); }
+    }
+}
+
+// key space and suffix of a scanned key, `family` being the key builder it came from
+fn parse_key(ks: &KeySpace, key: &str, family: fn(&KeySpace, &String) -> String) -> Option<(KeySpace, String)> {
+    let _ks = KeySpace { prefix: ks.prefix.to_owned(), namespace: namespace_of_key(ks, key) };
+    let _template = family(&_ks, &String::new());
+    // hash-tagged families wrap the suffix in braces
+    let _suffix = match _template.strip_suffix("{}") {
+        Some(head) => key.strip_prefix(head)?.strip_prefix('{')?.strip_suffix('}')?,
+        None => key.strip_prefix(_template.as_str())?
+    };
+    Some((_ks, _suffix.to_string()))
+}
+
+fn check_slangs(con: &mut RedisConnection, ks: &KeySpace, repair: bool, report: &mut Report) -> redis::RedisResult<()> {
+    scan_each(con, &format!("{}*idx:str:slg:*", ks.prefix), BATCH, &mut |con, key| {
+        let (_ks, _slang) = match parse_key(ks, key, str_k_slang) { Some(x) => x, None => return Ok(()) };
+        report.scanned += 1;
+        let _id : Option<String> = con.get(key)?;
+        let _id = match _id { Some(x) => x, None => return Ok(()) };
+
+        let _has_hash : bool = con.exists(hash_k_id(&_ks, &_id))?;
+        if !_has_hash {
+            report.record(DANGLING_SLANG, key);
+            if repair {
+                let _ : () = con.del(key)?;
+                let _ : () = con.zrem(zset_k_id(&_ks, &_id), &_slang)?;
+                report.repaired += 1;
+            }
+            return Ok(());
+        }
+        let _score : Option<f64> = con.zscore(zset_k_id(&_ks, &_id), &_slang)?;
+        if _score.is_none() {
+            report.record(UNINDEXED_SLANG, key);
+            if repair {
+                let _ : () = con.zadd(zset_k_id(&_ks, &_id), &_slang, 0)?;
+                report.repaired += 1;
+            }
+        }
+        Ok(())
+    })
+}
+
+fn check_zsets(con: &mut RedisConnection, ks: &KeySpace, repair: bool, report: &mut Report) -> redis::RedisResult<()> {
+    scan_each(con, &format!("{}*slgs:zset:id:*", ks.prefix), BATCH, &mut |con, key| {
+        let (_ks, _id) = match parse_key(ks, key, zset_k_id) { Some(x) => x, None => return Ok(()) };
+        report.scanned += 1;
+        let _has_hash : bool = con.exists(hash_k_id(&_ks, &_id))?;
+        let _slangs : Vec<String> = con.zrange(key, 0, -1)?;
+
+        if !_has_hash {
+            report.record(ORPHAN_ZSET, key);
+            if repair {
+                for x in _slangs.iter() {
+                    let _target : Option<String> = con.get(str_k_slang(&_ks, x))?;
+                    if _target.as_ref() == Some(&_id) { let _ : () = con.del(str_k_slang(&_ks, x))?; }
+                }
+                let _ : () = con.del(key)?;
+                report.repaired += 1;
+            }
+            return Ok(());
+        }
+        for x in _slangs.iter() {
+            let _target : Option<String> = con.get(str_k_slang(&_ks, x))?;
+            if _target.as_ref() != Some(&_id) {
+                report.record(STALE_ZSET_MEMBER, &format!("{} {}", key, x));
+                if repair {
+                    let _ : () = con.zrem(key, x)?;
+                    report.repaired += 1;
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+// creation times of slangs no longer in the slang zset of their bucket
+fn check_created_zsets(con: &mut RedisConnection, ks: &KeySpace, repair: bool, report: &mut Report) -> redis::RedisResult<()> {
+    scan_each(con, &format!("{}*slgs:zset:created:*", ks.prefix), BATCH, &mut |con, key| {
+        let (_ks, _id) = match parse_key(ks, key, zset_k_created) { Some(x) => x, None => return Ok(()) };
+        report.scanned += 1;
+        let _has_hash : bool = con.exists(hash_k_id(&_ks, &_id))?;
+        if !_has_hash {
+            report.record(ORPHAN_ZSET, key);
+            if repair {
+                let _ : () = con.del(key)?;
+                report.repaired += 1;
+            }
+            return Ok(());
+        }
+        let _slangs : Vec<String> = con.zrange(key, 0, -1)?;
+        for x in _slangs.iter() {
+            let _score : Option<f64> = con.zscore(zset_k_id(&_ks, &_id), x)?;
+            if _score.is_none() {
+                report.record(STALE_ZSET_MEMBER, &format!("{} {}", key, x));
+                if repair {
+                    let _ : () = con.zrem(key, x)?;
+                    report.repaired += 1;
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+fn check_labels(con: &mut RedisConnection, ks: &KeySpace, repair: bool, report: &mut Report) -> redis::RedisResult<()> {
+    scan_each(con, &format!("{}*bkt:hash:labels:*", ks.prefix), BATCH, &mut |con, key| {
+        let (_ks, _id) = match parse_key(ks, key, hash_k_labels) { Some(x) => x, None => return Ok(()) };
+        report.scanned += 1;
+        let _has_hash : bool = con.exists(hash_k_id(&_ks, &_id))?;
+        if _has_hash { return Ok(()) }
+        report.record(ORPHAN_LABELS, key);
+        if repair {
+            // the reverse index is dropped by the label set scan once the labels hash is gone
+            let _ : () = con.del(key)?;
+            report.repaired += 1;
+        }
+        Ok(())
+    })?;
+    scan_each(con, &format!("{}*lbl:set:*", ks.prefix), BATCH, &mut |con, key| {
+        let (_ks, _pair) = match parse_key(ks, key, set_k_label) { Some(x) => x, None => return Ok(()) };
+        let (_key, _value) = match _pair.split_once('=') { Some(x) => x, None => return Ok(()) };
+        report.scanned += 1;
+        let _ids : Vec<String> = con.smembers(key)?;
+        for x in _ids.iter() {
+            let _current : Option<String> = con.hget(hash_k_labels(&_ks, x), _key)?;
+            if _current.as_deref() == Some(_value) { continue }
+            report.record(STALE_LABEL, &format!("{} {}", key, x));
+            if repair {
+                let _ : () = con.srem(key, x)?;
+                report.repaired += 1;
+            }
+        }
+        Ok(())
+    })
+}
+
+fn check_terms(con: &mut RedisConnection, ks: &KeySpace, repair: bool, report: &mut Report) -> redis::RedisResult<()> {
+    scan_each(con, &format!("{}*fts:set:id:*", ks.prefix), BATCH, &mut |con, key| {
+        let (_ks, _id) = match parse_key(ks, key, set_k_terms) { Some(x) => x, None => return Ok(()) };
+        report.scanned += 1;
+        let _has_hash : bool = con.exists(hash_k_id(&_ks, &_id))?;
+        if _has_hash { return Ok(()) }
+        report.record(ORPHAN_TERMS, key);
+        if repair {
+            // the term sets are cleaned by the term scan once the terms of the bucket are gone
+            let _ : () = con.del(key)?;
+            report.repaired += 1;
+        }
+        Ok(())
+    })?;
+    scan_each(con, &format!("{}*fts:set:term:*", ks.prefix), BATCH, &mut |con, key| {
+        let (_ks, _term) = match parse_key(ks, key, set_k_term) { Some(x) => x, None => return Ok(()) };
+        report.scanned += 1;
+        let _ids : Vec<String> = con.smembers(key)?;
+        for x in _ids.iter() {
+            let _indexed : bool = con.sismember(set_k_terms(&_ks, x), &_term)?;
+            if _indexed { continue }
+            report.record(STALE_TERM, &format!("{} {}", key, x));
+            if repair {
+                let _ : () = con.srem(key, x)?;
+                report.repaired += 1;
+            }
+        }
+        Ok(())
+    })
+}
+
+fn check_hashes(con: &mut RedisConnection, ks: &KeySpace, slang: &SlangConfig, repair: bool, report: &mut Report, tags: &mut HashSet<(String, String)>) -> redis::RedisResult<()> {
+    scan_each(con, &format!("{}*bkt:hash:id:*", ks.prefix), BATCH, &mut |con, key| {
+        let (_ks, _id) = match parse_key(ks, key, hash_k_id) { Some(x) => x, None => return Ok(()) };
+        report.scanned += 1;
+        let _tag : Option<String> = con.hget(key, HASH_KF_CHUNK_TAG)?;
+        if let Some(x) = _tag { tags.insert((_ks.namespace.to_owned(), x)); }
+        // a default slang is cut from the id, so keys not named by one are left alone
+        if !is_id(&_id) { return Ok(()) }
+        let _count : isize = con.zcard(zset_k_id(&_ks, &_id))?;
+        if _count > 0 { return Ok(()) }
+
+        // a bucket is only ever stored with its mime and content, anything less is not worth a slang
+        let _fields : Vec<Option<String>> = con.hget(key, &[HASH_KF_MIME, HASH_KF_DATA, HASH_KF_CHUNKS])?;
+        if _fields.iter().all(Option::is_none) {
+            report.record(ORPHAN_HASH, key);
+            if repair {
+                let _ : () = con.del(key)?;
+                report.repaired += 1;
+            }
+            return Ok(());
+        }
+
+        report.record(HASH_WITHOUT_SLANG, key);
+        if repair {
+            // regenerate the default slang unless another bucket took it meanwhile
+            let _slang = get_slang_from_id(&_id, slang);
+            let _target : Option<String> = con.get(str_k_slang(&_ks, &_slang))?;
+            match _target {
+                Some(x) if x != _id => log::warn!("Cannot regenerate slang {} for {}, taken by {}", _slang, _id, x),
+                _ => {
+                    let _ : () = con.set(str_k_slang(&_ks, &_slang), &_id)?;
+                    let _ : () = con.zadd(zset_k_id(&_ks, &_id), &_slang, 0)?;
+                    let _ : () = con.zadd(zset_k_created(&_ks, &_id), &_slang, now_millis() as i64)?;
+                    report.repaired += 1;
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+// chunk keys end with their index after the tag
+fn chunk_family(ks: &KeySpace, tag: &String) -> String {
+    let _key = str_k_chunk(ks, tag, 0);
+    _key.strip_suffix(":0").unwrap_or(&_key).to_string()
+}
+
+// whether the chunks tagged `tag` got their last chunk within the grace period, as uploads still running do
+fn in_flight(con: &mut RedisConnection, ks: &KeySpace, tag: &String, now: u128) -> redis::RedisResult<bool> {
+    let _score : Option<f64> = con.zscore(zset_k_uploads(ks), tag)?;
+    Ok(_score.is_some_and(|x| now.saturating_sub(x as u128) < UPLOAD_GRACE.as_millis()))
+}
+
+// chunk tags of every manifest
+fn collect_tags(con: &mut RedisConnection, ks: &KeySpace, tags: &mut HashSet<(String, String)>) -> redis::RedisResult<()> {
+    scan_each(con, &format!("{}*bkt:hash:id:*", ks.prefix), BATCH, &mut |con, key| {
+        let (_ks, _) = match parse_key(ks, key, hash_k_id) { Some(x) => x, None => return Ok(()) };
+        let _tag : Option<String> = con.hget(key, HASH_KF_CHUNK_TAG)?;
+        if let Some(x) = _tag { tags.insert((_ks.namespace, x)); }
+        Ok(())
+    })
+}
+
+fn check_chunks(con: &mut RedisConnection, ks: &KeySpace, repair: bool, report: &mut Report, tags: &HashSet<(String, String)>) -> redis::RedisResult<()> {
+    let _now = now_millis();
+    let mut _candidates = Vec::new();
+    scan_each(con, &format!("{}*bkt:chunk:tag:*", ks.prefix), BATCH, &mut |con, key| {
+        let _head = match key.rsplit_once(':') { Some((x, _)) => x, None => return Ok(()) };
+        let (_ks, _tag) = match parse_key(ks, _head, chunk_family) { Some(x) => x, None => return Ok(()) };
+        report.scanned += 1;
+        if tags.contains(&(_ks.namespace.to_owned(), _tag.to_owned())) || in_flight(con, &_ks, &_tag, _now)? { return Ok(()) }
+        _candidates.push((key.to_owned(), _ks, _tag));
+        Ok(())
+    })?;
+
+    // an upload may have committed its manifest after the hash scan and left flight since,
+    // its manifest is then found by a scan started after it was seen out of flight
+    let mut _tags = HashSet::new();
+    if !_candidates.is_empty() { collect_tags(con, ks, &mut _tags)?; }
+    for (key, _ks, _tag) in _candidates.into_iter() {
+        if _tags.contains(&(_ks.namespace.to_owned(), _tag.to_owned())) || in_flight(con, &_ks, &_tag, _now)? { continue }
+        report.record(ORPHAN_CHUNK, &key);
+        if repair {
+            let _ : () = con.del(&key)?;
+            report.repaired += 1;
+        }
+    }
+    // tags of uploads that died before committing or aborting
+    scan_each(con, &format!("{}*bkt:zset:uploads", ks.prefix), BATCH, &mut |con, key| {
+        report.scanned += 1;
+        let _stale : Vec<String> = con.zrangebyscore(key, "-inf", (_now.saturating_sub(UPLOAD_GRACE.as_millis())) as f64)?;
+        for x in _stale.iter() {
+            report.record(STALE_UPLOAD, &format!("{} {}", key, x));
+            if repair {
+                let _ : () = con.zrem(key, x)?;
+                report.repaired += 1;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Scans the slang, zset, label, search term, hash and chunk key families of every namespace, optionally repairing what it finds
+pub fn run(client: &RedisPool, ks: &KeySpace, slang: &SlangConfig, repair: bool) -> redis::RedisResult<Report> {
+    let mut con = get_connection(client)?;
+    let mut report = Report::default();
+
+    // slangs first, dropping dangling ones may leave zsets empty which Redis removes
+    check_slangs(&mut con, ks, repair, &mut report)?;
+    check_zsets(&mut con, ks, repair, &mut report)?;
+    check_created_zsets(&mut con, ks, repair, &mut report)?;
+    check_labels(&mut con, ks, repair, &mut report)?;
+    check_terms(&mut con, ks, repair, &mut report)?;
+    // chunks are written before their manifest, those of uploads still in flight are left alone
+    let mut tags = HashSet::new();
+    check_hashes(&mut con, ks, slang, repair, &mut report, &mut tags)?;
+    check_chunks(&mut con, ks, repair, &mut report, &tags)?;
+
+    log::info!("Consistency check scanned {} keys, found {} issue categories, repaired {}",
+        report.scanned, report.issues.len(), report.repaired);
+    Ok(report)
+}
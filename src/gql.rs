@@ -1,11 +1,20 @@
+use chrono::{DateTime, TimeZone, Utc};
 use juniper::FieldResult;
 use juniper::graphql_value;
 use juniper::{EmptySubscription, RootNode};
-use juniper::{GraphQLInputObject, GraphQLObject};
+use juniper::{GraphQLEnum, GraphQLInputObject, GraphQLObject};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
 use std::io::{Error, ErrorKind};
 
+use crate::chunk::*;
+use crate::codec::{compress_if_smaller, Codec};
+use crate::fsck;
 use crate::hash::*;
+use crate::metrics::{observe_resolver, FIELD_ERRORS, SLANG_COLLISIONS};
 use crate::redis::*;
+use crate::search::{is_indexable, snippet, terms};
+use crate::sniff::{default_mime, mime_matches, sniff, MimeCheck};
 
 #[derive(GraphQLObject)]
 #[graphql(description = "Bucket")]
@@ -19,23 +28,119 @@ struct Bucket {
 struct BucketContext {
     id:   String,   // SHA-256 of context
     data: String,   // data, in string
-    mime: String    // mime of data
+    mime: String,   // mime of data
+    sniffed_mime: Option<String>  // mime sniffed from data when contradicting the declared one
 }
 
-#[derive(GraphQLObject)]
+#[derive(GraphQLObject, Serialize)]
 #[graphql(description = "Metadata for bucket")]
-struct BucketMeta {
+#[serde(rename_all = "camelCase")]
+pub struct BucketMeta {
     id:     String,         // SHA-256 of context
     slang:  Vec<String>,   // queryable slang for BucketContext
     rsa:    Option<String>, // RSA public key for encryption at rest
+    slang_stats:      Vec<SlangStat>,          // slangs with their statistics, ordered as slang
+    labels:           Vec<Label>,              // ordered by key
+    size_bytes:       f64,                     // size of the uncompressed content
+    created_at:       Option<DateTime<Utc>>,   // null for buckets created before timestamps were kept
+    updated_at:       Option<DateTime<Utc>>,   // last create, slang or label change
+    last_accessed_at: Option<DateTime<Utc>>,   // last bucketContext or download
+    read_count:       f64                      // bucketContext queries and downloads
+}
+
+#[derive(GraphQLObject, Serialize)]
+#[graphql(description = "Slang with its statistics")]
+#[serde(rename_all = "camelCase")]
+pub struct SlangStat {
+    slang:      String,
+    hits:       f64,                    // bucketContext queries and downloads through the slang
+    created_at: Option<DateTime<Utc>>   // null for slangs set before creation times were kept
+}
+
+#[derive(GraphQLObject, Serialize)]
+#[graphql(description = "Label attached to a bucket")]
+pub struct Label {
+    key:   String,
+    value: String
+}
+
+#[derive(GraphQLInputObject)]
+#[graphql(description = "Label to attach to a bucket")]
+struct LabelInput {
+    key:   String,  // [A-Za-z0-9_.-], up to 64 characters
+    value: String   // up to 256 bytes
+}
+
+#[derive(GraphQLEnum)]
+#[graphql(description = "Order of the slangs of a bucket")]
+pub enum SlangOrder {
+    Name,        // alphabetical
+    Popularity,  // most hits first
+    Age          // oldest first
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "Bucket whose content matches a search")]
+struct SearchHit {
+    cursor:      String,      // passed as `after` to resume after this hit
+    bucket_meta: BucketMeta,
+    snippet:     String       // content around the first matching term
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "Page of search hits, ordered by id")]
+struct SearchPage {
+    hits:          Vec<SearchHit>,
+    end_cursor:    Option<String>,
+    has_next_page: bool
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "Storage usage of the calling owner")]
+struct Usage {
+    bytes:   f64,        // total bytes stored
+    buckets: i32,        // number of buckets stored
+    limit:   UsageLimit  // quota applied to owner
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "Storage quota, null being unlimited")]
+struct UsageLimit {
+    bytes:   Option<f64>,
+    buckets: Option<i32>
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "Storage usage of a namespace")]
+struct NamespaceUsage {
+    namespace: String,
+    bytes:     f64,
+    buckets:   i32
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "Result of a consistency check over slang, zset and hash keys")]
+struct ConsistencyReport {
+    scanned:  i32,
+    repaired: i32,
+    issues:   Vec<ConsistencyIssue>
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "Inconsistency category found by a consistency check")]
+struct ConsistencyIssue {
+    category: String,
+    count:    i32,
+    samples:  Vec<String>  // first offending keys
 }
 
 #[derive(GraphQLInputObject)]
 #[graphql(description = "New bucket")]
 struct NewBucket {
     data:   String,
-    mime:   String,
-    rsa:    Option<String> 
+    mime:   Option<String>,  // sniffed from data when omitted
+    rsa:    Option<String>,
+    labels: Option<Vec<LabelInput>>
 }
 
 #[derive(GraphQLInputObject)]
@@ -49,51 +154,287 @@ impl juniper::Context for RedisCtx {}
 
 pub struct QueryRoot;
 
-fn query_bucket_context(context: &RedisCtx, slang: String) -> FieldResult<BucketContext> {
+/// Byte range `start..end` asked by `offset` and `length`, the end being `usize::MAX` without a length
+fn byte_range(offset: Option<i32>, length: Option<i32>) -> FieldResult<(usize, usize)> {
+    let _offset = offset.unwrap_or(0);
+    if _offset < 0 || length.is_some_and(|x| x < 0) { return Err(new_field_error(Box::new(Error::from(ErrorKind::InvalidInput)), "INVALID_RANGE")) }
+    let _start = _offset as usize;
+    Ok((_start, length.map_or(usize::MAX, |x| _start.saturating_add(x as usize))))
+}
+
+// shrinks bytes cut out of UTF-8 text to whole characters, dropping the parts of characters split at either end
+fn clamp_to_chars(mut bytes: Vec<u8>) -> Vec<u8> {
+    let _cut = bytes.iter().take(3).take_while(|x| (**x & 0xC0) == 0x80).count();
+    bytes.drain(.._cut);
+    // the last character is split when its first byte announces more bytes than remain
+    if let Some(i) = bytes.iter().rev().take(4).position(|x| (*x & 0xC0) != 0x80) {
+        let _at = bytes.len() - 1 - i;
+        let _len = match bytes[_at] { x if x >= 0xF0 => 4, x if x >= 0xE0 => 3, x if x >= 0xC0 => 2, _ => 1 };
+        if _at + _len > bytes.len() { bytes.truncate(_at); }
+    }
+    bytes
+}
+
+// inline data as stored with its codec, `None` for chunked payloads
+fn query_stored_data(context: &RedisCtx, client: &RedisPool, id: &String) -> FieldResult<Option<(Codec, Vec<u8>)>> {
+    // get encoding
+    let _encoding_res = get_hash_kfv(client, &hash_k_id(&context.keys, id), &HASH_KF_ENCODING.to_string());
+    if let Err(e) = _encoding_res { return Err(new_field_error(Box::new(e), "GET_HASH_KF_ENCODING")) }
+    let _codec = if let Some(RedisRtn::Payload(x)) = _encoding_res.unwrap() { Codec::from_name(&x).unwrap_or(Codec::None) } else { Codec::None };
+
+    // get data
+    let _data_res = get_hash_bytes(client, &hash_k_id(&context.keys, id), &HASH_KF_DATA.to_string());
+    if let Err(e) = _data_res { return Err(new_field_error(Box::new(e), "GET_HASH_KF_DATA")) }
+    Ok(_data_res.unwrap().map(|x| (_codec, x)))
+}
+
+fn decode_data(codec: Codec, data: Vec<u8>) -> FieldResult<Vec<u8>> {
+    if codec == Codec::None { return Ok(data) }
+    match codec.decompress(&data) {
+        Ok(x) => Ok(x),
+        Err(e) => Err(new_field_error(Box::new(e), "DECOMPRESS"))
+    }
+}
+
+fn query_chunked_data(context: &RedisCtx, client: &RedisPool, id: &String, start: usize, end: usize) -> FieldResult<Vec<u8>> {
+    // get manifest
+    let _manifest_res = get_manifest(client, &context.keys, id);
+    if let Err(e) = _manifest_res { return Err(new_field_error(Box::new(e), "GET_HASH_KF_CHUNKS")) }
+    let _manifest = match _manifest_res.unwrap() { Some(x) => x, None => return Ok(Vec::new()) };
+
+    // get chunks covering the range
+    let _reader = ChunkReader::new(client, &context.keys, _manifest).range(start, end);
+    let mut _data = Vec::with_capacity(_reader.len());
+    for x in _reader {
+        match x {
+            Ok(x) => _data.extend_from_slice(&x),
+            Err(e) => return Err(new_field_error(Box::new(e), "GET_STR_K_CHUNK"))
+        }
+    }
+    Ok(_data)
+}
+
+// leading text of bucket `id` as indexed for search, `None` when it is not UTF-8
+fn query_index_text(context: &RedisCtx, client: &RedisPool, id: &String) -> FieldResult<Option<String>> {
+    let _end = context.storage.search_index_bytes;
+    let _bytes = match query_stored_data(context, client, id)? {
+        Some((_codec, x)) => {
+            let mut _bytes = decode_data(_codec, x)?;
+            _bytes.truncate(_end);
+            _bytes
+        },
+        None => query_chunked_data(context, client, id, 0, _end)?
+    };
+    match String::from_utf8(_bytes) {
+        Ok(x) => Ok(Some(x)),
+        // cut within a character
+        Err(e) if e.utf8_error().error_len().is_none() => {
+            let _valid = e.utf8_error().valid_up_to();
+            let mut _bytes = e.into_bytes();
+            _bytes.truncate(_valid);
+            Ok(String::from_utf8(_bytes).ok())
+        },
+        Err(_) => Ok(None)
+    }
+}
+
+fn query_bucket_context(context: &RedisCtx, client: &RedisPool, slang: String, offset: Option<i32>, length: Option<i32>) -> FieldResult<BucketContext> {
+    let (_start, _end) = byte_range(offset, length)?;
+
     // get id
-    let _id_res = get_kv(&context.client, &str_k_slang(&slang));
+    let _id_res = get_kv(client, &str_k_slang(&context.keys, &slang));
     if let Err(e) = _id_res { return Err(new_field_error(Box::new(e), "GET_STR_K_SLANG")) }
-    if let None = _id_res.as_ref().unwrap() { return Err(new_field_error(Box::new(Error::from(ErrorKind::NotFound)), "NO_SLANG")) }
+    if _id_res.as_ref().unwrap().is_none() { return Err(new_field_error(Box::new(Error::from(ErrorKind::NotFound)), "NO_SLANG")) }
     let _id = if let Some(RedisRtn::Payload(_id)) = _id_res.as_ref().unwrap() { _id.to_owned() } else { "".to_owned() };
 
-    // get data
-    let _data_res = get_hash_kfv(&context.client, &hash_k_id(&_id), &HASH_KF_DATA.to_string());
-    if let Err(e) = _data_res { return Err(new_field_error(Box::new(e), "GET_HASH_KF_DATA")) }
-    let _data = if let Some(RedisRtn::Payload(x)) = _data_res.unwrap() { x.to_owned() } else { "".to_owned() };
+    // get data, chunked payloads have none
+    let _bytes = match query_stored_data(context, client, &_id)? {
+        Some((_codec, x)) => {
+            let mut _bytes = decode_data(_codec, x)?;
+            _bytes.truncate(_end);
+            _bytes.drain(.._start.min(_bytes.len()));
+            _bytes
+        },
+        None => query_chunked_data(context, client, &_id, _start, _end)?
+    };
+    let _bytes = if offset.is_some() || length.is_some() { clamp_to_chars(_bytes) } else { _bytes };
+    let _data = match String::from_utf8(_bytes) {
+        Ok(x) => x,
+        Err(e) => return Err(new_field_error(Box::new(e), "DATA_NOT_UTF8"))
+    };
 
     // get mime
-    let _mime_res = get_hash_kfv(&context.client, &hash_k_id(&_id), &HASH_KF_MIME.to_string());
+    let _mime_res = get_hash_kfv(client, &hash_k_id(&context.keys, &_id), &HASH_KF_MIME.to_string());
     if let Err(e) = _mime_res { return Err(new_field_error(Box::new(e), "GET_HASH_KF_MIME")) }
     let _mime = if let Some(RedisRtn::Payload(x)) = _mime_res.unwrap() { x.to_owned() } else { "".to_owned() };
 
+    // get sniffed mime
+    let _sniffed_res = get_hash_kfv(client, &hash_k_id(&context.keys, &_id), &HASH_KF_SNIFFED_MIME.to_string());
+    if let Err(e) = _sniffed_res { return Err(new_field_error(Box::new(e), "GET_HASH_KF_SNIFFED_MIME")) }
+    let _sniffed_mime = if let Some(RedisRtn::Payload(x)) = _sniffed_res.unwrap() { Some(x) } else { None };
+
     Ok(BucketContext {
         id: _id,
         data: _data,
-        mime: _mime
+        mime: _mime,
+        sniffed_mime: _sniffed_mime
     })
 }
 
-fn query_bucket_meta(context: &RedisCtx, slang: String) -> FieldResult<BucketMeta> {
+fn query_bucket_meta(context: &RedisCtx, client: &RedisPool, slang: String, order: SlangOrder) -> FieldResult<BucketMeta> {
     // get id
-    let _id_res = get_kv(&context.client, &str_k_slang(&slang));
+    let _id_res = get_kv(client, &str_k_slang(&context.keys, &slang));
     if let Err(e) = _id_res { return Err(new_field_error(Box::new(e), "GET_STR_K_SLANG")) }
-    if let None = _id_res.as_ref().unwrap() { return Err(new_field_error(Box::new(Error::from(ErrorKind::NotFound)), "NO_SLANG")) }
+    if _id_res.as_ref().unwrap().is_none() { return Err(new_field_error(Box::new(Error::from(ErrorKind::NotFound)), "NO_SLANG")) }
     let _id = if let Some(RedisRtn::Payload(_id)) = _id_res.as_ref().unwrap() { _id.to_owned() } else { "".to_owned() };
 
-    // get slangs
-    let _slang_res = get_sorted_kv(&context.client, &zset_k_id(&_id));
-    if let Err(e) = _slang_res { return Err(new_field_error(Box::new(e), "GET_ZSET_K_ID")) }
-    let _slang = if let Some(RedisRtn::Payloads(_slang)) = _slang_res.unwrap() { _slang } else { Vec::new() };
-
     // get rsa
-    let _rsa_res = get_hash_kfv(&context.client, &hash_k_id(&_id), &HASH_KF_RSA.to_string());
+    let _rsa_res = get_hash_kfv(client, &hash_k_id(&context.keys, &_id), &HASH_KF_RSA.to_string());
     if let Err(e) = _rsa_res { return Err(new_field_error(Box::new(e), "GET_HASH_KF_RSA")) }
     let _option_rsa = if let Some(RedisRtn::Payload(_rsa)) = _rsa_res.unwrap() { Some(_rsa) } else { None };
 
+    bucket_meta(client, &context.keys, _id, _option_rsa, order)
+}
+
+fn get_hash_time(client: &RedisPool, id: &String, key: &str, message: &str) -> FieldResult<Option<DateTime<Utc>>> {
+    let _res = get_hash_kfv(client, id, &key.to_string());
+    if let Err(e) = _res { return Err(new_field_error(Box::new(e), message)) }
+    Ok(match _res.unwrap() {
+        Some(RedisRtn::Payload(x)) => x.parse::<i64>().ok().and_then(|x| Utc.timestamp_millis_opt(x).single()),
+        _ => None
+    })
+}
+
+fn query_slang_stats(client: &RedisPool, keys: &KeySpace, id: &String, order: SlangOrder) -> FieldResult<Vec<SlangStat>> {
+    // get hits, the score of the slang zset
+    let _hits_res = get_sorted_scores(client, &zset_k_id(keys, id));
+    if let Err(e) = _hits_res { return Err(new_field_error(Box::new(e), "GET_ZSET_K_ID")) }
+
+    // get creation times, absent for slangs set before they were kept
+    let _created_res = get_sorted_scores(client, &zset_k_created(keys, id));
+    if let Err(e) = _created_res { return Err(new_field_error(Box::new(e), "GET_ZSET_K_CREATED")) }
+    let _created : HashMap<String, f64> = _created_res.unwrap().into_iter().collect();
+
+    let mut _stats : Vec<SlangStat> = _hits_res.unwrap().into_iter().map(|(slang, hits)| SlangStat {
+        created_at: _created.get(&slang).and_then(|x| Utc.timestamp_millis_opt(*x as i64).single()),
+        slang,
+        hits
+    }).collect();
+    match order {
+        SlangOrder::Name => _stats.sort_by(|a, b| a.slang.cmp(&b.slang)),
+        SlangOrder::Popularity => _stats.sort_by(|a, b| b.hits.total_cmp(&a.hits).then(a.created_at.cmp(&b.created_at)).then(a.slang.cmp(&b.slang))),
+        SlangOrder::Age => _stats.sort_by(|a, b| a.created_at.cmp(&b.created_at).then(a.slang.cmp(&b.slang)))
+    }
+    Ok(_stats)
+}
+
+// completes the metadata of bucket `id` with its slangs, size, timestamps and access statistics
+fn bucket_meta(client: &RedisPool, keys: &KeySpace, id: String, rsa: Option<String>, order: SlangOrder) -> FieldResult<BucketMeta> {
+    let _key = hash_k_id(keys, &id);
+    let _slang_stats = query_slang_stats(client, keys, &id, order)?;
+
+    // get labels
+    let _labels_res = get_hash_all(client, &hash_k_labels(keys, &id));
+    if let Err(e) = _labels_res { return Err(new_field_error(Box::new(e), "GET_HASH_K_LABELS")) }
+    let mut _labels : Vec<Label> = _labels_res.unwrap().into_iter().map(|(key, value)| Label { key, value }).collect();
+    _labels.sort_by(|a, b| a.key.cmp(&b.key));
+
     Ok(BucketMeta {
-        id: _id,
-        slang: _slang.to_owned(),
-        rsa: _option_rsa
+        labels: _labels,
+        slang: _slang_stats.iter().map(|x| x.slang.to_owned()).collect(),
+        slang_stats: _slang_stats,
+        size_bytes: get_hash_int(client, &_key, HASH_KF_SIZE, "GET_HASH_KF_SIZE")? as f64,
+        created_at: get_hash_time(client, &_key, HASH_KF_CREATED_AT, "GET_HASH_KF_CREATED_AT")?,
+        updated_at: get_hash_time(client, &_key, HASH_KF_UPDATED_AT, "GET_HASH_KF_UPDATED_AT")?,
+        last_accessed_at: get_hash_time(client, &_key, HASH_KF_ACCESSED_AT, "GET_HASH_KF_ACCESSED_AT")?,
+        read_count: get_hash_int(client, &_key, HASH_KF_READS, "GET_HASH_KF_READS")? as f64,
+        id,
+        rsa
+    })
+}
+
+fn touch_bucket(context: &RedisCtx, id: &String, field: &str) -> FieldResult<()> {
+    let _now = Utc::now().timestamp_millis().to_string();
+    if let Err(e) = set_hash_kfv(&context.client, &hash_k_id(&context.keys, id), &field.to_string(), &_now) {
+        return Err(new_field_error(Box::new(e), &format!("SET_HASH_KF_{}", field.to_uppercase())));
+    }
+    Ok(())
+}
+
+/// Counts a read of bucket `id` through `slang` on the primary, replicas being read-only.
+/// A failure is only logged as the read itself succeeded.
+fn record_access(context: &RedisCtx, id: &String, slang: Option<&String>) {
+    if let Some(x) = slang {
+        if let Err(e) = incr_sorted_kvs(&context.client, &zset_k_id(&context.keys, id), x, 1) {
+            log::warn!("Cannot count hit of slang {}: {}", x, e);
+        }
+    }
+    // a read from a lagging replica or racing deleteBucket must not recreate the bucket hash
+    let _now = Utc::now().timestamp_millis().to_string();
+    match incr_hash_xx(&context.client, &hash_k_id(&context.keys, id), &HASH_KF_READS.to_string(), 1, &HASH_KF_ACCESSED_AT.to_string(), &_now) {
+        Ok(Some(_)) => (),
+        Ok(None) => log::warn!("Bucket {} deleted before its read was counted", id),
+        Err(e) => log::warn!("Cannot count read of bucket {}: {}", id, e)
+    }
+}
+
+fn get_hash_int(client: &RedisPool, id: &String, key: &str, message: &str) -> FieldResult<isize> {
+    let _res = get_hash_kfv(client, id, &key.to_string());
+    if let Err(e) = _res { return Err(new_field_error(Box::new(e), message)) }
+    Ok(if let Some(RedisRtn::Payload(x)) = _res.unwrap() { x.parse::<isize>().unwrap_or(0) } else { 0 })
+}
+
+fn query_usage(context: &RedisCtx) -> FieldResult<Usage> {
+    let _bytes = get_hash_int(&context.reader, &hash_k_owner(&context.keys, &context.owner), HASH_KF_BYTES, "GET_HASH_KF_BYTES")?;
+    let _buckets = get_hash_int(&context.reader, &hash_k_owner(&context.keys, &context.owner), HASH_KF_BUCKETS, "GET_HASH_KF_BUCKETS")?;
+
+    Ok(Usage {
+        bytes: _bytes as f64,
+        buckets: _buckets as i32,
+        limit: UsageLimit {
+            bytes: context.quota.bytes.map(|x| x as f64),
+            buckets: context.quota.buckets.map(|x| x as i32)
+        }
+    })
+}
+
+fn query_namespaces(context: &RedisCtx) -> FieldResult<Vec<NamespaceUsage>> {
+    if !context.admin { return Err(new_field_error(Box::new(Error::from(ErrorKind::PermissionDenied)), "NOT_ADMIN")) }
+
+    // get namespaces
+    let _ns_res = get_set_kv(&context.reader, &set_k_namespaces(&context.keys));
+    if let Err(e) = _ns_res { return Err(new_field_error(Box::new(e), "GET_SET_K_NAMESPACES")) }
+    let mut _ns = if let Some(RedisRtn::Payloads(_ns)) = _ns_res.unwrap() { _ns } else { Vec::new() };
+    _ns.sort();
+
+    let mut _usages = Vec::new();
+    for x in _ns {
+        let _bytes = get_hash_int(&context.reader, &hash_k_namespace(&context.keys, &x), HASH_KF_BYTES, "GET_HASH_KF_BYTES")?;
+        let _buckets = get_hash_int(&context.reader, &hash_k_namespace(&context.keys, &x), HASH_KF_BUCKETS, "GET_HASH_KF_BUCKETS")?;
+        _usages.push(NamespaceUsage {
+            namespace: x,
+            bytes: _bytes as f64,
+            buckets: _buckets as i32
+        });
+    }
+    Ok(_usages)
+}
+
+fn check_consistency(context: &RedisCtx, repair: bool) -> FieldResult<ConsistencyReport> {
+    if !context.admin { return Err(new_field_error(Box::new(Error::from(ErrorKind::PermissionDenied)), "NOT_ADMIN")) }
+
+    let _report = fsck::run(&context.client, &context.keys, &context.slang, repair);
+    if let Err(e) = _report { return Err(new_field_error(Box::new(e), "FSCK")) }
+    let _report = _report.unwrap();
+
+    Ok(ConsistencyReport {
+        scanned: _report.scanned as i32,
+        repaired: _report.repaired as i32,
+        issues: _report.issues.into_iter().map(|x| ConsistencyIssue {
+            category: x.category.to_string(),
+            count: x.count as i32,
+            samples: x.samples
+        }).collect()
     })
 }
 
@@ -103,12 +444,36 @@ impl QueryRoot {
         env!("CARGO_PKG_VERSION")
     }
 
-    pub fn bucketContext(context: &RedisCtx, slang: String) -> FieldResult<BucketContext> {
-        query_bucket_context(context, slang)
+    pub fn bucketContext(context: &RedisCtx, slang: String, offset: Option<i32>, length: Option<i32>) -> FieldResult<BucketContext> {
+        observe_resolver("bucketContext", || {
+            let _bc = query_bucket_context(context, &context.reader, slang.to_owned(), offset, length)?;
+            record_access(context, &_bc.id, Some(&slang));
+            Ok(_bc)
+        })
+    }
+
+    pub fn bucketMeta(context: &RedisCtx, slang: String, order: Option<SlangOrder>) -> FieldResult<BucketMeta> {
+        observe_resolver("bucketMeta", || query_bucket_meta(context, &context.reader, slang, order.unwrap_or(SlangOrder::Name)))
     }
 
-    pub fn bucketMeta(context: &RedisCtx, slang: String) -> FieldResult<BucketMeta> {
-        query_bucket_meta(context, slang)
+    pub fn usage(context: &RedisCtx) -> FieldResult<Usage> {
+        observe_resolver("usage", || query_usage(context))
+    }
+
+    pub fn search(context: &RedisCtx, query: String, first: Option<i32>, after: Option<String>) -> FieldResult<SearchPage> {
+        observe_resolver("search", || query_search(context, query, first, after))
+    }
+
+    pub fn bucketsByLabel(context: &RedisCtx, key: String, value: String) -> FieldResult<Vec<BucketMeta>> {
+        observe_resolver("bucketsByLabel", || query_buckets_by_label(context, key, value))
+    }
+
+    pub fn namespaces(context: &RedisCtx) -> FieldResult<Vec<NamespaceUsage>> {
+        observe_resolver("namespaces", || query_namespaces(context))
+    }
+
+    pub fn checkConsistency(context: &RedisCtx) -> FieldResult<ConsistencyReport> {
+        observe_resolver("checkConsistency", || check_consistency(context, false))
     }
 }
 
@@ -116,177 +481,784 @@ pub struct MutationRoot;
 
 fn new_field_error(e: Box<dyn std::error::Error>, message: &str) -> juniper::FieldError {
     log::error!("{}", e);
+    // calls rejected by an open circuit breaker share one code whichever step failed
+    let message = match e.downcast_ref::<redis::RedisError>() {
+        Some(x) if is_breaker_open(x) => "SERVICE_UNAVAILABLE",
+        _ => message
+    };
+    FIELD_ERRORS.with_label_values(&[message]).inc();
     juniper::FieldError::new(
-        &message,
+        message,
         graphql_value!({ "internal_error": message })
     )
 }
 
+fn incr_usage(context: &RedisCtx, key: &String, bytes: isize, buckets: isize) -> Result<(isize, isize), juniper::FieldError> {
+    let _bytes_res = incr_hash_kfv(&context.client, key, &HASH_KF_BYTES.to_string(), bytes);
+    if let Err(e) = _bytes_res { return Err(new_field_error(Box::new(e), "INCR_HASH_KF_BYTES")) }
+    let _buckets_res = incr_hash_kfv(&context.client, key, &HASH_KF_BUCKETS.to_string(), buckets);
+    if let Err(e) = _buckets_res { return Err(new_field_error(Box::new(e), "INCR_HASH_KF_BUCKETS")) }
+
+    let _bytes = if let Ok(RedisRtn::Status(Status::Int(x))) = _bytes_res { x } else { 0 };
+    let _buckets = if let Ok(RedisRtn::Status(Status::Int(x))) = _buckets_res { x } else { 0 };
+    Ok((_bytes, _buckets))
+}
+
+/// Fails with `QUOTA_EXCEEDED` when the owner has no room left for one more bucket of `size` bytes.
+/// Streamed uploads check it before each chunk, as they only reserve their quota once complete.
+pub fn check_quota(context: &RedisCtx, size: usize) -> FieldResult<()> {
+    if context.quota.bytes.is_none() && context.quota.buckets.is_none() { return Ok(()) }
+    let _key = hash_k_owner(&context.keys, &context.owner);
+    let _bytes = get_hash_int(&context.client, &_key, HASH_KF_BYTES, "GET_HASH_KF_BYTES")?;
+    let _buckets = get_hash_int(&context.client, &_key, HASH_KF_BUCKETS, "GET_HASH_KF_BUCKETS")?;
+
+    let _over_bytes = matches!(context.quota.bytes, Some(limit) if _bytes.saturating_add(size as isize) > limit);
+    let _over_buckets = matches!(context.quota.buckets, Some(limit) if _buckets >= limit);
+    if _over_bytes || _over_buckets {
+        return Err(new_field_error(Box::new(Error::from(ErrorKind::Other)), "QUOTA_EXCEEDED"))
+    }
+    Ok(())
+}
+
+/// Writes the next bytes of a streamed upload, once the quota has room for the upload so far
+pub fn write_upload(context: &RedisCtx, writer: &mut ChunkWriter, bytes: &[u8]) -> FieldResult<()> {
+    check_quota(context, writer.size() + bytes.len())?;
+    if let Err(e) = writer.write(bytes) { return Err(new_field_error(Box::new(e), "SET_STR_K_CHUNK")) }
+    Ok(())
+}
+
+fn reserve_quota(context: &RedisCtx, size: isize) -> Result<(), juniper::FieldError> {
+    // reserve first so concurrent creates cannot both slip under the limit
+    let _key = hash_k_owner(&context.keys, &context.owner);
+    let (_bytes, _buckets) = incr_usage(context, &_key, size, 1)?;
+
+    let _over_bytes = matches!(context.quota.bytes, Some(limit) if _bytes > limit);
+    let _over_buckets = matches!(context.quota.buckets, Some(limit) if _buckets > limit);
+    if _over_bytes || _over_buckets {
+        incr_usage(context, &_key, -size, -1)?;
+        return Err(new_field_error(Box::new(Error::from(ErrorKind::Other)), "QUOTA_EXCEEDED"))
+    }
+    Ok(())
+}
+
 fn set_slang(context: &RedisCtx, meta_change: &MetaChange) -> Result<(), juniper::FieldError> {
     // str: slang -> id
-    if let Err(e) = set_kv(&context.client, &str_k_slang(&meta_change.slang), &meta_change.id) {
+    if let Err(e) = set_kv(&context.client, &str_k_slang(&context.keys, &meta_change.slang), &meta_change.id) {
         return Err(new_field_error(Box::new(e), "SET_STR_K_SLANG")); 
     }
-    // zset: id -> slang
-    if let Err(e) = set_sorted_kvs(&context.client, &zset_k_id(&meta_change.id), &meta_change.slang, 0) {
+    // zset: id -> slang, scored by hits kept when the slang is set again
+    if let Err(e) = add_sorted_kvs(&context.client, &zset_k_id(&context.keys, &meta_change.id), &meta_change.slang, 0) {
         return Err(new_field_error(Box::new(e), "SET_ZSET_K_ID")); 
     }
+    // zset: id -> slang, scored by creation time
+    if let Err(e) = add_sorted_kvs(&context.client, &zset_k_created(&context.keys, &meta_change.id), &meta_change.slang, Utc::now().timestamp_millis()) {
+        return Err(new_field_error(Box::new(e), "SET_ZSET_K_CREATED")); 
+    }
     Ok(())
 }
 
-#[juniper::graphql_object(context = RedisCtx)]
-impl MutationRoot {
-    fn deleteBucket(context: &RedisCtx, id: String) -> FieldResult<Bucket> {
-        // get slangs
-        let _slang_res = get_sorted_kv(&context.client, &zset_k_id(&id));
-        if let Err(e) = _slang_res { return Err(new_field_error(Box::new(e), "GET_ZSET_K_ID")) }
-        let _slang = if let Some(RedisRtn::Payloads(_slang)) = _slang_res.unwrap() { _slang } else { Vec::new() };
-        if _slang.len() == 0 { return Err(new_field_error(Box::new(Error::from(ErrorKind::NotFound)), "NO_ID"))  }
-
-        let _bc = query_bucket_context(context, _slang[0].to_owned());
-        if let Err(e) = _bc { return Err(e) }
-        let _bm = query_bucket_meta(context, _slang[0].to_owned());
-        if let Err(e) = _bm { return Err(e) }
-
-        // del hash
-        if let Err(e) = del_kv(&context.client, &hash_k_id(&id)) {
-            return Err(new_field_error(Box::new(e), "DEL_HASH_K_ID")); 
+fn mutate_delete_bucket(context: &RedisCtx, id: String) -> FieldResult<Bucket> {
+    // get slangs
+    let _slang_res = get_sorted_kv(&context.client, &zset_k_id(&context.keys, &id));
+    if let Err(e) = _slang_res { return Err(new_field_error(Box::new(e), "GET_ZSET_K_ID")) }
+    let _slang = if let Some(RedisRtn::Payloads(_slang)) = _slang_res.unwrap() { _slang } else { Vec::new() };
+    if _slang.is_empty() { return Err(new_field_error(Box::new(Error::from(ErrorKind::NotFound)), "NO_ID"))  }
+
+    let _bc = query_bucket_context(context, &context.client, _slang[0].to_owned(), None, None)?;
+    let _bm = query_bucket_meta(context, &context.client, _slang[0].to_owned(), SlangOrder::Name)?;
+
+    // get owner, absent for buckets created before quotas
+    let _owner_res = get_hash_kfv(&context.client, &hash_k_id(&context.keys, &id), &HASH_KF_OWNER.to_string());
+    if let Err(e) = _owner_res { return Err(new_field_error(Box::new(e), "GET_HASH_KF_OWNER")) }
+    let _size = get_hash_int(&context.client, &hash_k_id(&context.keys, &id), HASH_KF_SIZE, "GET_HASH_KF_SIZE")?;
+    let _manifest_res = get_manifest(&context.client, &context.keys, &id);
+    if let Err(e) = _manifest_res { return Err(new_field_error(Box::new(e), "GET_HASH_KF_CHUNKS")) }
+
+    // del hash
+    if let Err(e) = del_kv(&context.client, &hash_k_id(&context.keys, &id)) {
+        return Err(new_field_error(Box::new(e), "DEL_HASH_K_ID")); 
+    }
+    // del chunks
+    if let Some(_manifest) = _manifest_res.unwrap() {
+        if let Err(e) = del_chunks(&context.client, &context.keys, &_manifest) {
+            return Err(new_field_error(Box::new(e), "DEL_STR_K_CHUNK")); 
         }
-        // del each slang
-        for x in _slang {
-            if let Err(e) = del_kv(&context.client, &str_k_slang(&x)) {
-                return Err(new_field_error(Box::new(e), "DEL_STR_K_SLANG")); 
-            }
+    }
+    // del each slang
+    for x in _slang {
+        if let Err(e) = del_kv(&context.client, &str_k_slang(&context.keys, &x)) {
+            return Err(new_field_error(Box::new(e), "DEL_STR_K_SLANG")); 
         }
-        // del zset
-        if let Err(e) = del_kv(&context.client, &zset_k_id(&id)) {
-            return Err(new_field_error(Box::new(e), "DEL_ZSET_K_ID")); 
+    }
+    // del zset
+    if let Err(e) = del_kv(&context.client, &zset_k_id(&context.keys, &id)) {
+        return Err(new_field_error(Box::new(e), "DEL_ZSET_K_ID")); 
+    }
+    if let Err(e) = del_kv(&context.client, &zset_k_created(&context.keys, &id)) {
+        return Err(new_field_error(Box::new(e), "DEL_ZSET_K_CREATED")); 
+    }
+    // del labels with their reverse index
+    let _labels_res = get_hash_all(&context.client, &hash_k_labels(&context.keys, &id));
+    if let Err(e) = _labels_res { return Err(new_field_error(Box::new(e), "GET_HASH_K_LABELS")) }
+    for (k, v) in _labels_res.unwrap() {
+        if let Err(e) = del_set_kvs(&context.client, &set_k_label(&context.keys, &label_pair(&k, &v)), &id) {
+            return Err(new_field_error(Box::new(e), "DEL_SET_K_LABEL")); 
         }
-        
-        Ok(Bucket {
-            bucket_context: _bc.unwrap(),
-            bucket_meta: _bm.unwrap()
-        })
     }
+    if let Err(e) = del_kv(&context.client, &hash_k_labels(&context.keys, &id)) {
+        return Err(new_field_error(Box::new(e), "DEL_HASH_K_LABELS")); 
+    }
+    // del search terms
+    unindex_bucket(context, &id)?;
+    // release owner quota and namespace usage
+    if let Some(RedisRtn::Payload(_owner)) = _owner_res.unwrap() {
+        incr_usage(context, &hash_k_owner(&context.keys, &_owner), -_size, -1)?;
+        incr_usage(context, &hash_k_namespace(&context.keys, &context.keys.namespace), -_size, -1)?;
+    }
+    
+    Ok(Bucket {
+        bucket_context: _bc,
+        bucket_meta: _bm
+    })
+}
 
-    fn dropSlang(context: &RedisCtx, meta_change: MetaChange) -> FieldResult<BucketMeta> {
-        // get slangs
-        let _slang_res_bef = get_sorted_kv(&context.client, &zset_k_id(&meta_change.id));
-        if let Err(e) = _slang_res_bef { return Err(new_field_error(Box::new(e), "GET_ZSET_K_ID")) }
-        if let Some(RedisRtn::Payloads(_slang)) = _slang_res_bef.as_ref().unwrap() {
-            if _slang.len() == 1 && _slang[0].eq(&meta_change.slang) {
-                return Err(new_field_error(Box::new(Error::from(ErrorKind::Unsupported)), "ID_LAST_SLANG")) 
-            }
+fn mutate_drop_slang(context: &RedisCtx, meta_change: MetaChange) -> FieldResult<BucketMeta> {
+    // get slangs
+    let _slang_res_bef = get_sorted_kv(&context.client, &zset_k_id(&context.keys, &meta_change.id));
+    if let Err(e) = _slang_res_bef { return Err(new_field_error(Box::new(e), "GET_ZSET_K_ID")) }
+    if let Some(RedisRtn::Payloads(_slang)) = _slang_res_bef.as_ref().unwrap() {
+        if _slang.len() == 1 && _slang[0].eq(&meta_change.slang) {
+            return Err(new_field_error(Box::new(Error::from(ErrorKind::Unsupported)), "ID_LAST_SLANG")) 
         }
-        if let None = _slang_res_bef.as_ref().unwrap() { return Err(new_field_error(Box::new(Error::from(ErrorKind::NotFound)), "NO_ID")) }
+    }
+    if _slang_res_bef.as_ref().unwrap().is_none() { return Err(new_field_error(Box::new(Error::from(ErrorKind::NotFound)), "NO_ID")) }
 
-        // try get id by slang
-        let _id_res = get_kv(&context.client, &str_k_slang(&meta_change.slang));
-        if let Err(e) = _id_res { return Err(new_field_error(Box::new(e), "GET_STR_K_SLANG")) }
-        if let Some(RedisRtn::Payload(_id)) = _id_res.unwrap() {
-            if !_id.eq(&meta_change.id) {
-                return Err(new_field_error(Box::new(Error::from(ErrorKind::Unsupported)), "ID_SLANG_MISMATCH")) 
-            }
+    // try get id by slang
+    let _id_res = get_kv(&context.client, &str_k_slang(&context.keys, &meta_change.slang));
+    if let Err(e) = _id_res { return Err(new_field_error(Box::new(e), "GET_STR_K_SLANG")) }
+    if let Some(RedisRtn::Payload(_id)) = _id_res.unwrap() {
+        if !_id.eq(&meta_change.id) {
+            return Err(new_field_error(Box::new(Error::from(ErrorKind::Unsupported)), "ID_SLANG_MISMATCH")) 
         }
+    }
 
-        // del slang -> id
-        if let Err(e) = del_kv(&context.client, &str_k_slang(&meta_change.slang)) {
-            return Err(new_field_error(Box::new(e), "DEL_STR_K_SLANG")); 
+    // del slang -> id
+    if let Err(e) = del_kv(&context.client, &str_k_slang(&context.keys, &meta_change.slang)) {
+        return Err(new_field_error(Box::new(e), "DEL_STR_K_SLANG")); 
+    }
+
+    // del zset: id -> slang
+    if let Err(e) = del_sorted_kvs(&context.client, &zset_k_id(&context.keys, &meta_change.id), &meta_change.slang) {
+        return Err(new_field_error(Box::new(e), "DEL_ZSET_K_ID")); 
+    }
+    if let Err(e) = del_sorted_kvs(&context.client, &zset_k_created(&context.keys, &meta_change.id), &meta_change.slang) {
+        return Err(new_field_error(Box::new(e), "DEL_ZSET_K_CREATED")); 
+    }
+
+    // try get rsa by id
+    let _rsa_res = get_hash_kfv(&context.client, &hash_k_id(&context.keys, &meta_change.id), &HASH_KF_RSA.to_string());
+    if let Err(e) = _rsa_res { return Err(new_field_error(Box::new(e), "GET_HASH_KF_RSA")) }
+    let _option_rsa = if let Some(RedisRtn::Payload(_rsa)) = _rsa_res.unwrap() { Some(_rsa) } else { None };
+
+    touch_bucket(context, &meta_change.id, HASH_KF_UPDATED_AT)?;
+    bucket_meta(&context.client, &context.keys, meta_change.id, _option_rsa, SlangOrder::Name)
+}
+
+const MAX_LABELS : usize = 64;
+
+fn label_pair(key: &str, value: &str) -> String { format!("{}={}", key, value) }
+
+fn validate_label_key(key: &str) -> FieldResult<()> {
+    if key.is_empty() || key.len() > 64 || !key.bytes().all(|x| x.is_ascii_alphanumeric() || matches!(x, b'_' | b'.' | b'-')) {
+        return Err(new_field_error(Box::new(Error::from(ErrorKind::InvalidInput)), "INVALID_LABEL"))
+    }
+    Ok(())
+}
+
+fn validate_labels(labels: &[LabelInput]) -> FieldResult<()> {
+    if labels.len() > MAX_LABELS { return Err(new_field_error(Box::new(Error::from(ErrorKind::InvalidInput)), "TOO_MANY_LABELS")) }
+    for x in labels {
+        validate_label_key(&x.key)?;
+        if x.value.len() > 256 { return Err(new_field_error(Box::new(Error::from(ErrorKind::InvalidInput)), "INVALID_LABEL")) }
+    }
+    Ok(())
+}
+
+fn set_labels(context: &RedisCtx, id: &String, labels: &[LabelInput]) -> FieldResult<()> {
+    validate_labels(labels)?;
+    let _key = hash_k_labels(&context.keys, id);
+    let _labels_res = get_hash_all(&context.client, &_key);
+    if let Err(e) = _labels_res { return Err(new_field_error(Box::new(e), "GET_HASH_K_LABELS")) }
+    let _labels : HashMap<String, String> = _labels_res.unwrap().into_iter().collect();
+    let _new = labels.iter().filter(|x| !_labels.contains_key(&x.key)).map(|x| &x.key).collect::<std::collections::HashSet<_>>().len();
+    if _labels.len() + _new > MAX_LABELS { return Err(new_field_error(Box::new(Error::from(ErrorKind::InvalidInput)), "TOO_MANY_LABELS")) }
+
+    for x in labels {
+        // previous value leaves the reverse index
+        if let Some(_prev) = _labels.get(&x.key).filter(|v| **v != x.value) {
+            if let Err(e) = del_set_kvs(&context.client, &set_k_label(&context.keys, &label_pair(&x.key, _prev)), id) {
+                return Err(new_field_error(Box::new(e), "DEL_SET_K_LABEL"));
+            }
+        }
+        // hash: key -> value, before the reverse index pointing at it
+        if let Err(e) = set_hash_kfv(&context.client, &_key, &x.key, &x.value) {
+            return Err(new_field_error(Box::new(e), "SET_HASH_K_LABELS"));
+        }
+        // set: key=value -> id
+        if let Err(e) = set_set_kvs(&context.client, &set_k_label(&context.keys, &label_pair(&x.key, &x.value)), id) {
+            return Err(new_field_error(Box::new(e), "SET_SET_K_LABEL"));
         }
+    }
+    Ok(())
+}
 
-        // del zset: id -> slang
-        if let Err(e) = del_sorted_kvs(&context.client, &zset_k_id(&meta_change.id), &meta_change.slang) {
-            return Err(new_field_error(Box::new(e), "DEL_ZSET_K_ID")); 
+fn remove_labels(context: &RedisCtx, id: &String, keys: &[String]) -> FieldResult<()> {
+    let _key = hash_k_labels(&context.keys, id);
+    for x in keys {
+        let _value_res = get_hash_kfv(&context.client, &_key, x);
+        if let Err(e) = _value_res { return Err(new_field_error(Box::new(e), "GET_HASH_K_LABELS")) }
+        let _value = match _value_res.unwrap() { Some(RedisRtn::Payload(v)) => v, _ => continue };
+        // reverse index first, it is checked against the hash
+        if let Err(e) = del_set_kvs(&context.client, &set_k_label(&context.keys, &label_pair(x, &_value)), id) {
+            return Err(new_field_error(Box::new(e), "DEL_SET_K_LABEL"));
         }
+        if let Err(e) = del_hash_kfv(&context.client, &_key, x) {
+            return Err(new_field_error(Box::new(e), "DEL_HASH_K_LABELS"));
+        }
+    }
+    Ok(())
+}
 
-        // try get rsa by id
-        let _rsa_res = get_hash_kfv(&context.client, &hash_k_id(&meta_change.id), &HASH_KF_RSA.to_string());
-        if let Err(e) = _rsa_res { return Err(new_field_error(Box::new(e), "GET_HASH_KF_RSA")) }
-        let _option_rsa = if let Some(RedisRtn::Payload(_rsa)) = _rsa_res.unwrap() { Some(_rsa) } else { None };
+// rsa of bucket `id`, checking it exists
+fn get_bucket_rsa(context: &RedisCtx, client: &RedisPool, id: &String) -> FieldResult<Option<String>> {
+    // get mime
+    let _mime_res = get_hash_kfv(client, &hash_k_id(&context.keys, id), &HASH_KF_MIME.to_string());
+    if let Err(e) = _mime_res { return Err(new_field_error(Box::new(e), "GET_HASH_KF_MIME")) }
+    if _mime_res.as_ref().unwrap().is_none() { return Err(new_field_error(Box::new(Error::from(ErrorKind::NotFound)), "NO_ID")) }
 
-        // get slangs
-        let _slang_res = get_sorted_kv(&context.client, &zset_k_id(&meta_change.id));
-        if let Err(e) = _slang_res { return Err(new_field_error(Box::new(e), "GET_ZSET_K_ID")) }
-        let _slang = if let Some(RedisRtn::Payloads(_slang)) = _slang_res.unwrap() { _slang } else { Vec::new() };
+    // get rsa
+    let _rsa_res = get_hash_kfv(client, &hash_k_id(&context.keys, id), &HASH_KF_RSA.to_string());
+    if let Err(e) = _rsa_res { return Err(new_field_error(Box::new(e), "GET_HASH_KF_RSA")) }
+    Ok(if let Some(RedisRtn::Payload(_rsa)) = _rsa_res.unwrap() { Some(_rsa) } else { None })
+}
 
-        Ok(BucketMeta {
-            id: meta_change.id,
-            slang: _slang,
-            rsa: _option_rsa
-        })
+const SEARCH_FIRST : i32 = 10;
+const SEARCH_MAX_FIRST : i32 = 100;
+// each term of a query costs a read of its whole term set
+const SEARCH_MAX_TERMS : usize = 8;
+
+/// Indexes the terms of bucket `id` for search when its mime is text, dropping a previous index otherwise
+fn index_bucket(context: &RedisCtx, id: &String, mime: &str) -> FieldResult<()> {
+    if !is_indexable(mime) { return unindex_bucket(context, id) }
+    if context.storage.search_index_bytes == 0 { return Ok(()) }
+    let _text = match query_index_text(context, &context.client, id)? { Some(x) => x, None => return Ok(()) };
+    let _terms : Vec<String> = terms(&_text).into_iter().collect();
+    if _terms.is_empty() { return Ok(()) }
+
+    // set: id -> terms, before the term sets pointing at it
+    if let Err(e) = set_set_many(&context.client, &set_k_terms(&context.keys, id), &_terms) {
+        return Err(new_field_error(Box::new(e), "SET_SET_K_TERMS"));
+    }
+    // set: term -> id
+    let _keys : Vec<String> = _terms.iter().map(|x| set_k_term(&context.keys, x)).collect();
+    if let Err(e) = set_sets_kvs(&context.client, &_keys, id) {
+        return Err(new_field_error(Box::new(e), "SET_SET_K_TERM"));
     }
+    Ok(())
+}
 
-    fn setSlang(context: &RedisCtx, meta_change: MetaChange) -> FieldResult<BucketMeta> {
-        // get mime
-        let _mime_res = get_hash_kfv(&context.client, &hash_k_id(&meta_change.id), &HASH_KF_MIME.to_string());
-        if let Err(e) = _mime_res { return Err(new_field_error(Box::new(e), "GET_HASH_KF_MIME")) }
-        if let None = _mime_res.as_ref().unwrap() { return Err(new_field_error(Box::new(Error::from(ErrorKind::NotFound)), "NO_ID")) }
+fn unindex_bucket(context: &RedisCtx, id: &String) -> FieldResult<()> {
+    let _terms_res = get_set_kv(&context.client, &set_k_terms(&context.keys, id));
+    if let Err(e) = _terms_res { return Err(new_field_error(Box::new(e), "GET_SET_K_TERMS")) }
+    let _terms = if let Some(RedisRtn::Payloads(x)) = _terms_res.unwrap() { x } else { return Ok(()) };
+    let _keys : Vec<String> = _terms.iter().map(|x| set_k_term(&context.keys, x)).collect();
+    if let Err(e) = del_sets_kvs(&context.client, &_keys, id) {
+        return Err(new_field_error(Box::new(e), "DEL_SET_K_TERM"));
+    }
+    if let Err(e) = del_kv(&context.client, &set_k_terms(&context.keys, id)) {
+        return Err(new_field_error(Box::new(e), "DEL_SET_K_TERMS"));
+    }
+    Ok(())
+}
 
-        // try get id by slang
-        let _id_res = get_kv(&context.client, &str_k_slang(&meta_change.slang));
-        if let Err(e) = _id_res { return Err(new_field_error(Box::new(e), "GET_STR_K_SLANG")) }
-        if let Some(_) = _id_res.unwrap() { return Err(new_field_error(Box::new(Error::from(ErrorKind::AlreadyExists)), "SLANG_EXISTS")) }
+fn query_search(context: &RedisCtx, query: String, first: Option<i32>, after: Option<String>) -> FieldResult<SearchPage> {
+    let _first = first.unwrap_or(SEARCH_FIRST);
+    if !(1..=SEARCH_MAX_FIRST).contains(&_first) { return Err(new_field_error(Box::new(Error::from(ErrorKind::InvalidInput)), "INVALID_FIRST")) }
+    let _terms = terms(&query);
+    if _terms.is_empty() { return Err(new_field_error(Box::new(Error::from(ErrorKind::InvalidInput)), "INVALID_QUERY")) }
+    if _terms.len() > SEARCH_MAX_TERMS { return Err(new_field_error(Box::new(Error::from(ErrorKind::InvalidInput)), "TOO_MANY_TERMS")) }
+    let client = &context.reader;
 
-        // id slang processing
-        if let Err(e) = set_slang(&context, &meta_change) {
-            return Err(e);
+    // ids holding every term, intersected here as term sets may live in different cluster slots
+    let mut _ids : Option<BTreeSet<String>> = None;
+    for x in _terms.iter() {
+        let _ids_res = get_set_kv(client, &set_k_term(&context.keys, x));
+        if let Err(e) = _ids_res { return Err(new_field_error(Box::new(e), "GET_SET_K_TERM")) }
+        let _members : BTreeSet<String> = if let Some(RedisRtn::Payloads(v)) = _ids_res.unwrap() { v.into_iter().collect() } else { BTreeSet::new() };
+        let _found = match _ids { Some(ids) => ids.intersection(&_members).cloned().collect(), None => _members };
+        if _found.is_empty() { return Ok(SearchPage { hits: Vec::new(), end_cursor: None, has_next_page: false }) }
+        _ids = Some(_found);
+    }
+
+    let _after = after.unwrap_or_default();
+    let mut _hits = Vec::new();
+    let mut _has_next = false;
+    for x in _ids.unwrap_or_default().into_iter().filter(|x| *x > _after) {
+        // skip entries a concurrent delete left behind
+        let _rsa = match get_bucket_rsa(context, client, &x) {
+            Ok(r) => r,
+            Err(e) if e.message() == "NO_ID" => continue,
+            Err(e) => return Err(e)
+        };
+        if _hits.len() == _first as usize {
+            _has_next = true;
+            break;
         }
+        let _text = query_index_text(context, client, &x)?.unwrap_or_default();
+        _hits.push(SearchHit {
+            cursor: x.to_owned(),
+            snippet: snippet(&_text, &_terms),
+            bucket_meta: bucket_meta(client, &context.keys, x, _rsa, SlangOrder::Name)?
+        });
+    }
+    Ok(SearchPage { end_cursor: _hits.last().map(|x| x.cursor.to_owned()), has_next_page: _has_next, hits: _hits })
+}
 
-        // try get rsa by id
-        let _rsa_res = get_hash_kfv(&context.client, &hash_k_id(&meta_change.id), &HASH_KF_RSA.to_string());
-        if let Err(e) = _rsa_res { return Err(new_field_error(Box::new(e), "GET_HASH_KF_RSA")) }
-        let _option_rsa = if let Some(RedisRtn::Payload(_rsa)) = _rsa_res.unwrap() { Some(_rsa) } else { None };
+fn mutate_set_labels(context: &RedisCtx, id: String, labels: Vec<LabelInput>) -> FieldResult<BucketMeta> {
+    let _rsa = get_bucket_rsa(context, &context.client, &id)?;
+    set_labels(context, &id, &labels)?;
+    touch_bucket(context, &id, HASH_KF_UPDATED_AT)?;
+    bucket_meta(&context.client, &context.keys, id, _rsa, SlangOrder::Name)
+}
 
-        // get slangs
-        let _slang_res = get_sorted_kv(&context.client, &zset_k_id(&meta_change.id));
-        if let Err(e) = _slang_res { return Err(new_field_error(Box::new(e), "GET_ZSET_K_ID")) }
-        let _slang = if let Some(RedisRtn::Payloads(_slang)) = _slang_res.unwrap() { _slang } else { Vec::new() };
+fn mutate_remove_labels(context: &RedisCtx, id: String, keys: Vec<String>) -> FieldResult<BucketMeta> {
+    let _rsa = get_bucket_rsa(context, &context.client, &id)?;
+    remove_labels(context, &id, &keys)?;
+    touch_bucket(context, &id, HASH_KF_UPDATED_AT)?;
+    bucket_meta(&context.client, &context.keys, id, _rsa, SlangOrder::Name)
+}
 
-        Ok(BucketMeta {
-            id: meta_change.id,
-            slang: _slang,
-            rsa: _option_rsa
-        })
+fn query_buckets_by_label(context: &RedisCtx, key: String, value: String) -> FieldResult<Vec<BucketMeta>> {
+    validate_label_key(&key)?;
+    let client = &context.reader;
+    // get ids
+    let _ids_res = get_set_kv(client, &set_k_label(&context.keys, &label_pair(&key, &value)));
+    if let Err(e) = _ids_res { return Err(new_field_error(Box::new(e), "GET_SET_K_LABEL")) }
+    let mut _ids = if let Some(RedisRtn::Payloads(x)) = _ids_res.unwrap() { x } else { Vec::new() };
+    _ids.sort();
+
+    let mut _metas = Vec::with_capacity(_ids.len());
+    for x in _ids {
+        // skip entries a concurrent change left behind
+        let _value_res = get_hash_kfv(client, &hash_k_labels(&context.keys, &x), &key);
+        if let Err(e) = _value_res { return Err(new_field_error(Box::new(e), "GET_HASH_K_LABELS")) }
+        if !matches!(_value_res.unwrap(), Some(RedisRtn::Payload(v)) if v == value) { continue }
+        let _rsa = match get_bucket_rsa(context, client, &x) {
+            Ok(r) => r,
+            Err(e) if e.message() == "NO_ID" => continue,
+            Err(e) => return Err(e)
+        };
+        _metas.push(bucket_meta(client, &context.keys, x, _rsa, SlangOrder::Name)?);
     }
+    Ok(_metas)
+}
 
-    fn createBucket(context: &RedisCtx, new_bucket: NewBucket) -> FieldResult<BucketMeta> {
-        //TODO: Validation
-
-        let _id = get_id_from_context(&new_bucket.data);
-        let _slang = get_slang_from_id(&_id);
-        
-        //TODO: Centralized error handling and rollback
-        // Add Bucket
-        // hash: mime
-        if let Err(e) = set_hash_kfv(&context.client, &hash_k_id(&_id), &HASH_KF_MIME.to_string(), &new_bucket.mime) {
-            return Err(new_field_error(Box::new(e), "SET_HASH_KF_MIME")); 
+fn mutate_set_slang(context: &RedisCtx, meta_change: MetaChange) -> FieldResult<BucketMeta> {
+    // get mime
+    let _mime_res = get_hash_kfv(&context.client, &hash_k_id(&context.keys, &meta_change.id), &HASH_KF_MIME.to_string());
+    if let Err(e) = _mime_res { return Err(new_field_error(Box::new(e), "GET_HASH_KF_MIME")) }
+    if _mime_res.as_ref().unwrap().is_none() { return Err(new_field_error(Box::new(Error::from(ErrorKind::NotFound)), "NO_ID")) }
+
+    // try get id by slang
+    let _id_res = get_kv(&context.client, &str_k_slang(&context.keys, &meta_change.slang));
+    if let Err(e) = _id_res { return Err(new_field_error(Box::new(e), "GET_STR_K_SLANG")) }
+    if _id_res.unwrap().is_some() { return Err(new_field_error(Box::new(Error::from(ErrorKind::AlreadyExists)), "SLANG_EXISTS")) }
+
+    // id slang processing
+    set_slang(context, &meta_change)?;
+
+    // try get rsa by id
+    let _rsa_res = get_hash_kfv(&context.client, &hash_k_id(&context.keys, &meta_change.id), &HASH_KF_RSA.to_string());
+    if let Err(e) = _rsa_res { return Err(new_field_error(Box::new(e), "GET_HASH_KF_RSA")) }
+    let _option_rsa = if let Some(RedisRtn::Payload(_rsa)) = _rsa_res.unwrap() { Some(_rsa) } else { None };
+
+    touch_bucket(context, &meta_change.id, HASH_KF_UPDATED_AT)?;
+    bucket_meta(&context.client, &context.keys, meta_change.id, _option_rsa, SlangOrder::Name)
+}
+
+/// Payload of a bucket being created, data of a GraphQL request or chunks a streamed upload already wrote
+pub enum NewPayload {
+    Data(String),
+    Chunks(Manifest)
+}
+
+impl NewPayload {
+    /// Size of the content in bytes, before any compression
+    pub fn size(&self) -> isize {
+        match self {
+            NewPayload::Data(x) => x.len() as isize,
+            NewPayload::Chunks(x) => x.size as isize
+        }
+    }
+}
+
+fn set_payload(context: &RedisCtx, id: &String, payload: &NewPayload) -> Result<(), juniper::FieldError> {
+    let _written;
+    let _manifest = match payload {
+        NewPayload::Chunks(x) => x,
+        NewPayload::Data(x) => {
+            // compressed data may fit inline where the data itself does not
+            let _compressed = compress_if_smaller(context.storage.compression, context.storage.compression_min_bytes, x.as_bytes());
+            if let Some(_compressed) = _compressed.filter(|c| c.len() <= context.storage.chunk_bytes) {
+                // hash: encoding, before the data it decodes
+                if let Err(e) = set_hash_kfv(&context.client, &hash_k_id(&context.keys, id), &HASH_KF_ENCODING.to_string(), &context.storage.compression.to_string()) {
+                    return Err(new_field_error(Box::new(e), "SET_HASH_KF_ENCODING"));
+                }
+                // hash: data
+                if let Err(e) = set_hash_bytes(&context.client, &hash_k_id(&context.keys, id), &HASH_KF_DATA.to_string(), &_compressed) {
+                    return Err(new_field_error(Box::new(e), "SET_HASH_KF_DATA"));
+                }
+                return Ok(());
+            }
+            if x.len() <= context.storage.chunk_bytes {
+                // hash: data
+                if let Err(e) = set_hash_kfv(&context.client, &hash_k_id(&context.keys, id), &HASH_KF_DATA.to_string(), x) {
+                    return Err(new_field_error(Box::new(e), "SET_HASH_KF_DATA")); 
+                }
+                return Ok(());
+            }
+            // str: chunks, tagged with the id
+            let mut _writer = ChunkWriter::new(&context.client, &context.keys, id, context.storage.chunk_bytes);
+            if let Err(e) = _writer.write(x.as_bytes()) { return Err(new_field_error(Box::new(e), "SET_STR_K_CHUNK")) }
+            _written = match _writer.finish() {
+                Ok((_, x)) => x,
+                Err(e) => return Err(new_field_error(Box::new(e), "SET_STR_K_CHUNK"))
+            };
+            &_written
         }
-        // hash: data
-        if let Err(e) = set_hash_kfv(&context.client, &hash_k_id(&_id), &HASH_KF_DATA.to_string(), &new_bucket.data) {
-            return Err(new_field_error(Box::new(e), "SET_HASH_KF_DATA")); 
+    };
+    // hash: manifest
+    if let Err(e) = set_manifest(&context.client, &context.keys, id, _manifest) {
+        return Err(new_field_error(Box::new(e), "SET_HASH_KF_CHUNKS")); 
+    }
+    Ok(())
+}
+
+// chunks of an upload that does not become a bucket
+fn discard_payload(context: &RedisCtx, payload: &NewPayload) {
+    if let NewPayload::Chunks(x) = payload {
+        if let Err(e) = del_chunks(&context.client, &context.keys, x) {
+            log::error!("Cannot discard chunks tagged {}, left for repairConsistency: {}", x.tag, e);
         }
-        // id slang processing
-        if let Err(e) = set_slang(&context, &MetaChange { id: _id.to_owned(), slang: _slang.to_owned() }) {
+    }
+}
+
+/// Mime of a new bucket from the declared one and its leading bytes, with the sniffed mime
+/// when it contradicts the declared one and `storage.mime_check` flags it
+fn resolve_mime(context: &RedisCtx, mime: Option<String>, head: &[u8], is_utf8: bool) -> FieldResult<(String, Option<String>)> {
+    let _mime = match mime {
+        Some(x) if !x.trim().is_empty() => x,
+        _ => return Ok((default_mime(head, is_utf8).to_string(), None))
+    };
+    let _sniffed = match sniff(head) {
+        Some(x) if context.storage.mime_check != MimeCheck::Off && !mime_matches(&_mime, x) => x,
+        _ => return Ok((_mime, None))
+    };
+    log::warn!("Declared mime {} of new bucket contradicts sniffed mime {}", _mime, _sniffed);
+    if context.storage.mime_check == MimeCheck::Reject {
+        return Err(new_field_error(Box::new(Error::from(ErrorKind::InvalidData)), "MIME_MISMATCH"));
+    }
+    Ok((_mime, Some(_sniffed.to_string())))
+}
+
+// state of the content of a bucket being created
+enum Claim {
+    Existing,  // content already stored, deduplicated
+    Claimed,   // new content, charged to this create
+    Pending    // new content claimed by a concurrent create, or one that failed midway
+}
+
+// claims new content for the owner, so that of concurrent creates only the first one is charged
+fn claim_bucket(context: &RedisCtx, id: &String) -> FieldResult<Claim> {
+    // get mime, existing content is deduplicated and charged to its first owner only
+    let _mime_res = get_hash_kfv(&context.client, &hash_k_id(&context.keys, id), &HASH_KF_MIME.to_string());
+    if let Err(e) = _mime_res { return Err(new_field_error(Box::new(e), "GET_HASH_KF_MIME")) }
+    if _mime_res.unwrap().is_some() { return Ok(Claim::Existing) }
+    // hash: owner, set only by the first create
+    match set_hash_nx(&context.client, &hash_k_id(&context.keys, id), &HASH_KF_OWNER.to_string(), &context.owner) {
+        Ok(true) => Ok(Claim::Claimed),
+        Ok(false) => Ok(Claim::Pending),
+        Err(e) => Err(new_field_error(Box::new(e), "SET_HASH_KF_OWNER"))
+    }
+}
+
+// undoes a create of new content failing after its quota was reserved, dropping the partial bucket
+// and releasing the reservation; slangs, labels and terms already written are left to repairConsistency
+fn rollback_bucket(context: &RedisCtx, id: &String, size: isize, payload: &NewPayload) {
+    discard_payload(context, payload);
+    match get_manifest(&context.client, &context.keys, id) {
+        Ok(Some(_manifest)) => if let Err(e) = del_chunks(&context.client, &context.keys, &_manifest) {
+            log::error!("Cannot discard chunks tagged {}, left for repairConsistency: {}", _manifest.tag, e);
+        },
+        Ok(None) => (),
+        Err(e) => log::error!("Cannot read manifest of failed bucket {}: {}", id, e)
+    }
+    if let Err(e) = del_kv(&context.client, &hash_k_id(&context.keys, id)) {
+        log::error!("Cannot discard hash of failed bucket {}: {}", id, e);
+    }
+    if let Err(e) = incr_usage(context, &hash_k_owner(&context.keys, &context.owner), -size, -1) {
+        log::error!("Cannot release quota of owner {}: {}", context.owner, e.message());
+    }
+}
+
+fn create_bucket(context: &RedisCtx, _id: String, payload: NewPayload, mime: (String, Option<String>), rsa: Option<String>, labels: Vec<LabelInput>) -> FieldResult<BucketMeta> {
+    let _claim = match claim_bucket(context, &_id) {
+        Ok(x) => x,
+        Err(e) => {
+            discard_payload(context, &payload);
             return Err(e);
         }
-        if let Some(ref _rsa) = new_bucket.rsa {
-            //TODO: RSA public key encryption for data
-            // hash: rsa
-            if let Err(e) = set_hash_kfv(&context.client, &hash_k_id(&_id), &HASH_KF_RSA.to_string(), &_rsa) {
-                return Err(new_field_error(Box::new(e), "SET_HASH_KF_RSA")); 
+    };
+    let _size = payload.size();
+    match _claim {
+        Claim::Existing => {
+            discard_payload(context, &payload);
+            add_bucket(context, &_id, &payload, &_claim, mime, &rsa, &labels)?;
+        },
+        Claim::Pending => {
+            // content may not be written yet, writing it again is harmless as the id covers it;
+            // chunks a failure leaves behind are dropped by repairConsistency once out of flight
+            add_bucket(context, &_id, &payload, &_claim, mime, &rsa, &labels)?;
+        },
+        Claim::Claimed => {
+            if let Err(e) = reserve_quota(context, _size) {
+                discard_payload(context, &payload);
+                if let Err(e) = del_hash_kfv(&context.client, &hash_k_id(&context.keys, &_id), &HASH_KF_OWNER.to_string()) {
+                    log::error!("Cannot release claim on bucket {}: {}", _id, e);
+                }
+                return Err(e);
+            }
+            if let Err(e) = add_bucket(context, &_id, &payload, &_claim, mime, &rsa, &labels) {
+                rollback_bucket(context, &_id, _size, &payload);
+                return Err(e);
             }
         }
+    }
+    bucket_meta(&context.client, &context.keys, _id, rsa, SlangOrder::Name)
+}
 
-        // Get slangs
-        let _slang_res = get_sorted_kv(&context.client, &zset_k_id(&_id));
-        if let Err(e) = _slang_res { return Err(new_field_error(Box::new(e), "GET_ZSET_K_ID")) }
-        let _slang = if let Some(RedisRtn::Payloads(_slang)) = _slang_res.unwrap() { _slang } else { Vec::new() };
+// writes of a create, the payload being written unless the content exists
+fn add_bucket(context: &RedisCtx, _id: &String, payload: &NewPayload, claim: &Claim, (mime, sniffed_mime): (String, Option<String>), rsa: &Option<String>, labels: &[LabelInput]) -> FieldResult<()> {
+    let _slang = get_slang_from_id(_id, &context.slang);
+    let _size = payload.size();
 
-        Ok(BucketMeta {
-            id: _id.to_owned(),
-            slang: _slang,
-            rsa: new_bucket.rsa
-        })
+    // payload before mime, which marks the bucket as existing, and only once as the id covers the content
+    if !matches!(claim, Claim::Existing) {
+        set_payload(context, _id, payload)?;
+    }
+    // hash: sniffed mime, following the mime it contradicts
+    let _sniffed_res = match sniffed_mime {
+        Some(ref x) => set_hash_kfv(&context.client, &hash_k_id(&context.keys, _id), &HASH_KF_SNIFFED_MIME.to_string(), x),
+        None => del_hash_kfv(&context.client, &hash_k_id(&context.keys, _id), &HASH_KF_SNIFFED_MIME.to_string())
+    };
+    if let Err(e) = _sniffed_res { return Err(new_field_error(Box::new(e), "SET_HASH_KF_SNIFFED_MIME")) }
+    // hash: mime
+    if let Err(e) = set_hash_kfv(&context.client, &hash_k_id(&context.keys, _id), &HASH_KF_MIME.to_string(), &mime) {
+        return Err(new_field_error(Box::new(e), "SET_HASH_KF_MIME")); 
+    }
+    if !matches!(claim, Claim::Existing) {
+        // hash: size
+        if let Err(e) = set_hash_kfv(&context.client, &hash_k_id(&context.keys, _id), &HASH_KF_SIZE.to_string(), &_size.to_string()) {
+            return Err(new_field_error(Box::new(e), "SET_HASH_KF_SIZE")); 
+        }
+    }
+    if matches!(claim, Claim::Claimed) {
+        // hash: created at
+        touch_bucket(context, _id, HASH_KF_CREATED_AT)?;
+    }
+    // hash: updated at
+    touch_bucket(context, _id, HASH_KF_UPDATED_AT)?;
+    // count default slangs already pointing at another bucket
+    let _id_res = get_kv(&context.client, &str_k_slang(&context.keys, &_slang));
+    if let Err(e) = _id_res { return Err(new_field_error(Box::new(e), "GET_STR_K_SLANG")) }
+    if let Some(RedisRtn::Payload(_other)) = _id_res.unwrap() {
+        if !_other.eq(_id) { SLANG_COLLISIONS.inc(); }
+    }
+    // id slang processing
+    set_slang(context, &MetaChange { id: _id.to_owned(), slang: _slang.to_owned() })?;
+    if let Some(ref _rsa) = rsa {
+        //TODO: RSA public key encryption for data
+        // hash: rsa
+        if let Err(e) = set_hash_kfv(&context.client, &hash_k_id(&context.keys, _id), &HASH_KF_RSA.to_string(), _rsa) {
+            return Err(new_field_error(Box::new(e), "SET_HASH_KF_RSA")); 
+        }
+    }
+    set_labels(context, _id, labels)?;
+    index_bucket(context, _id, &mime)?;
+    if matches!(claim, Claim::Claimed) {
+        // set: namespaces
+        if let Err(e) = set_set_kvs(&context.client, &set_k_namespaces(&context.keys), &context.keys.namespace) {
+            return Err(new_field_error(Box::new(e), "SET_SET_K_NAMESPACES")); 
+        }
+        // last, as the rollback of a failed create only releases the owner quota
+        incr_usage(context, &hash_k_namespace(&context.keys, &context.keys.namespace), _size, 1)?;
+    }
+    Ok(())
+}
+
+fn mutate_create_bucket(context: &RedisCtx, new_bucket: NewBucket) -> FieldResult<BucketMeta> {
+    //TODO: Validation
+
+    let _id = get_id_from_context(&new_bucket.data);
+    let _labels = new_bucket.labels.unwrap_or_default();
+    validate_labels(&_labels)?;
+    let _mime = resolve_mime(context, new_bucket.mime, new_bucket.data.as_bytes(), true)?;
+    create_bucket(context, _id, NewPayload::Data(new_bucket.data), _mime, new_bucket.rsa, _labels)
+}
+
+/// Creates the bucket of a streamed upload once its chunks are written, as `createBucket` does for inline data,
+/// `head` being the leading bytes of the upload its mime is sniffed from
+pub fn upload_bucket(context: &RedisCtx, writer: ChunkWriter, mime: Option<String>, head: &[u8]) -> FieldResult<BucketMeta> {
+    observe_resolver("uploadBucket", || {
+        let (id, manifest) = match writer.finish() {
+            Ok(x) => x,
+            Err(e) => return Err(new_field_error(Box::new(e), "SET_STR_K_CHUNK"))
+        };
+        let _payload = NewPayload::Chunks(manifest);
+        let _mime = match resolve_mime(context, mime, head, std::str::from_utf8(head).map_or_else(|e| e.error_len().is_none(), |_| true)) {
+            Ok(x) => x,
+            Err(e) => {
+                discard_payload(context, &_payload);
+                return Err(e);
+            }
+        };
+        create_bucket(context, id, _payload, _mime, None, Vec::new())
+    })
+}
+
+/// Content of a bucket being downloaded, chunks being read one at a time as the response is sent
+pub enum Content {
+    Data(Vec<u8>),
+    Chunks(ChunkReader),
+    Encoded(Codec, Vec<u8>)  // compressed inline data, sent as is to clients accepting the codec
+}
+
+impl Content {
+    /// Content as sent to clients not accepting its codec
+    pub fn decode(self) -> std::io::Result<Content> {
+        match self {
+            Content::Encoded(codec, x) => Ok(Content::Data(codec.decompress(&x)?)),
+            x => Ok(x)
+        }
+    }
+
+    /// Bytes `start..end` of decoded content, chunked content then only reading the chunks covering them
+    pub fn range(self, start: usize, end: usize) -> Content {
+        match self {
+            Content::Data(x) => {
+                let _end = end.min(x.len());
+                Content::Data(x[start.min(_end).._end].to_vec())
+            },
+            Content::Chunks(x) => Content::Chunks(x.range(start, end)),
+            x => x
+        }
+    }
+}
+
+pub struct Download {
+    pub id: String,
+    pub mime: String,
+    pub sniffed_mime: Option<String>,
+    pub size: usize,
+    pub content: Content
+}
+
+fn query_download(context: &RedisCtx, client: &RedisPool, id: String) -> FieldResult<Download> {
+    // get mime, absent for unknown ids
+    let _mime_res = get_hash_kfv(client, &hash_k_id(&context.keys, &id), &HASH_KF_MIME.to_string());
+    if let Err(e) = _mime_res { return Err(new_field_error(Box::new(e), "GET_HASH_KF_MIME")) }
+    let _mime = match _mime_res.unwrap() {
+        Some(RedisRtn::Payload(x)) => x,
+        _ => return Err(new_field_error(Box::new(Error::from(ErrorKind::NotFound)), "NO_ID"))
+    };
+
+    // get sniffed mime
+    let _sniffed_res = get_hash_kfv(client, &hash_k_id(&context.keys, &id), &HASH_KF_SNIFFED_MIME.to_string());
+    if let Err(e) = _sniffed_res { return Err(new_field_error(Box::new(e), "GET_HASH_KF_SNIFFED_MIME")) }
+    let _sniffed_mime = if let Some(RedisRtn::Payload(x)) = _sniffed_res.unwrap() { Some(x) } else { None };
+
+    // get manifest, else inline data
+    let _manifest_res = get_manifest(client, &context.keys, &id);
+    if let Err(e) = _manifest_res { return Err(new_field_error(Box::new(e), "GET_HASH_KF_CHUNKS")) }
+    if let Some(_manifest) = _manifest_res.unwrap() {
+        return Ok(Download { id, mime: _mime, sniffed_mime: _sniffed_mime, size: _manifest.size, content: Content::Chunks(ChunkReader::new(client, &context.keys, _manifest)) });
+    }
+    match query_stored_data(context, client, &id)? {
+        Some((Codec::None, x)) => Ok(Download { id, mime: _mime, sniffed_mime: _sniffed_mime, size: x.len(), content: Content::Data(x) }),
+        Some((_codec, x)) => {
+            let _size = get_hash_int(client, &hash_k_id(&context.keys, &id), HASH_KF_SIZE, "GET_HASH_KF_SIZE")? as usize;
+            Ok(Download { id, mime: _mime, sniffed_mime: _sniffed_mime, size: _size, content: Content::Encoded(_codec, x) })
+        },
+        None => Ok(Download { id, mime: _mime, sniffed_mime: _sniffed_mime, size: 0, content: Content::Data(Vec::new()) })
+    }
+}
+
+/// Looks up the bucket behind `slang` for a streamed download from the query pool
+pub fn download_bucket(context: &RedisCtx, slang: String) -> FieldResult<Download> {
+    observe_resolver("downloadBucket", || {
+        // get id
+        let _id_res = get_kv(&context.reader, &str_k_slang(&context.keys, &slang));
+        if let Err(e) = _id_res { return Err(new_field_error(Box::new(e), "GET_STR_K_SLANG")) }
+        let _id = match _id_res.unwrap() {
+            Some(RedisRtn::Payload(x)) => x,
+            _ => return Err(new_field_error(Box::new(Error::from(ErrorKind::NotFound)), "NO_SLANG"))
+        };
+        let _download = query_download(context, &context.reader, _id)?;
+        record_access(context, &_download.id, Some(&slang));
+        Ok(_download)
+    })
+}
+
+/// Looks up bucket `id` for a streamed download from the query pool, ids not being SHA-256 digests are unknown
+pub fn download_id(context: &RedisCtx, id: String) -> FieldResult<Download> {
+    observe_resolver("downloadId", || {
+        if !is_id(&id) {
+            return Err(new_field_error(Box::new(Error::from(ErrorKind::NotFound)), "NO_ID"))
+        }
+        let _download = query_download(context, &context.reader, id)?;
+        record_access(context, &_download.id, None);
+        Ok(_download)
+    })
+}
+
+#[juniper::graphql_object(context = RedisCtx)]
+impl MutationRoot {
+    fn repairConsistency(context: &RedisCtx) -> FieldResult<ConsistencyReport> {
+        observe_resolver("repairConsistency", || check_consistency(context, true))
+    }
+
+    fn deleteBucket(context: &RedisCtx, id: String) -> FieldResult<Bucket> {
+        observe_resolver("deleteBucket", || mutate_delete_bucket(context, id))
+    }
+
+    fn dropSlang(context: &RedisCtx, meta_change: MetaChange) -> FieldResult<BucketMeta> {
+        observe_resolver("dropSlang", || mutate_drop_slang(context, meta_change))
+    }
+
+    fn setSlang(context: &RedisCtx, meta_change: MetaChange) -> FieldResult<BucketMeta> {
+        observe_resolver("setSlang", || mutate_set_slang(context, meta_change))
+    }
+
+    fn setLabels(context: &RedisCtx, id: String, labels: Vec<LabelInput>) -> FieldResult<BucketMeta> {
+        observe_resolver("setLabels", || mutate_set_labels(context, id, labels))
+    }
+
+    fn removeLabels(context: &RedisCtx, id: String, keys: Vec<String>) -> FieldResult<BucketMeta> {
+        observe_resolver("removeLabels", || mutate_remove_labels(context, id, keys))
+    }
+
+    fn createBucket(context: &RedisCtx, new_bucket: NewBucket) -> FieldResult<BucketMeta> {
+        observe_resolver("createBucket", || mutate_create_bucket(context, new_bucket))
     }
 }
 
@@ -294,4 +1266,65 @@ pub type Schema = RootNode<'static, QueryRoot, MutationRoot, EmptySubscription<R
 
 pub fn create_schema() -> Schema {
     Schema::new(QueryRoot {}, MutationRoot {}, EmptySubscription::new())
+}
+
+/// Metrics label of a GraphQL request, its operation name when that names a root field of the schema.
+/// Clients choose operation names freely, so others are counted as `other` to keep label cardinality bounded.
+pub fn operation_label(schema: &Schema, operation: Option<&str>) -> String {
+    let is_root_field = |x: &str| schema.schema.concrete_query_type().field_by_name(x).is_some()
+        || schema.schema.concrete_mutation_type().is_some_and(|t| t.field_by_name(x).is_some());
+    match operation {
+        None => "anonymous".to_string(),
+        Some(x) if !x.starts_with("__") && is_root_field(x) => x.to_string(),
+        Some(_) => "other".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operation_label_bounded_by_schema() {
+        let schema = create_schema();
+        assert_eq!(operation_label(&schema, None), "anonymous");
+        assert_eq!(operation_label(&schema, Some("bucketContext")), "bucketContext");
+        assert_eq!(operation_label(&schema, Some("createBucket")), "createBucket");
+        assert_eq!(operation_label(&schema, Some("GetMyBucket-1234")), "other");
+        assert_eq!(operation_label(&schema, Some("__typename")), "other");
+    }
+
+    #[test]
+    fn ranges_clamped_to_whole_characters() {
+        let text = "aé€😀b".as_bytes();
+        let clamp = |start: usize, end: usize| String::from_utf8(clamp_to_chars(text[start..end].to_vec())).unwrap();
+        assert_eq!(clamp(0, text.len()), "aé€😀b");
+        // cut inside é at the start, inside 😀 at the end
+        assert_eq!(clamp(2, 9), "€");
+        assert_eq!(clamp(2, 10), "€😀");
+        assert_eq!(clamp(4, 7), "");
+        assert_eq!(clamp(1, 2), "");
+    }
+
+    fn label(key: &str, value: &str) -> LabelInput { LabelInput { key: key.to_string(), value: value.to_string() } }
+
+    fn label_error(labels: &[LabelInput]) -> Option<String> {
+        validate_labels(labels).err().map(|e| e.message().to_string())
+    }
+
+    #[test]
+    fn label_keys_and_values_validated() {
+        assert_eq!(label_error(&[label("env", "prod"), label("team.name-2_b", "")]), None);
+        // an = would make the reverse index key ambiguous
+        assert_eq!(label_error(&[label("a=b", "c")]).as_deref(), Some("INVALID_LABEL"));
+        assert_eq!(label_error(&[label("", "x")]).as_deref(), Some("INVALID_LABEL"));
+        assert_eq!(label_error(&[label("é", "x")]).as_deref(), Some("INVALID_LABEL"));
+        assert_eq!(label_error(&[label(&"k".repeat(64), &"v".repeat(256))]), None);
+        assert_eq!(label_error(&[label(&"k".repeat(65), "x")]).as_deref(), Some("INVALID_LABEL"));
+        assert_eq!(label_error(&[label("k", &"v".repeat(257))]).as_deref(), Some("INVALID_LABEL"));
+
+        let many: Vec<LabelInput> = (0..=MAX_LABELS).map(|x| label(&format!("k{}", x), "v")).collect();
+        assert_eq!(label_error(&many[..MAX_LABELS]), None);
+        assert_eq!(label_error(&many).as_deref(), Some("TOO_MANY_LABELS"));
+    }
 }
\ No newline at end of file
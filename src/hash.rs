@@ -1,6 +1,44 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
 use sha2::{Sha256, Digest};
 
-fn pronounceable_hash(hash: &String) -> String {
+pub const ID_LENGTH : usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SlangAlgorithm {
+    Pronounceable, // alternating vowels and consonants
+    Hex            // leading characters of the id
+}
+
+impl FromStr for SlangAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pronounceable" => Ok(SlangAlgorithm::Pronounceable),
+            "hex" => Ok(SlangAlgorithm::Hex),
+            _ => Err(format!("unknown slang algorithm {}, expected pronounceable or hex", s))
+        }
+    }
+}
+
+/// How default slangs are derived from bucket ids
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SlangConfig {
+    pub algorithm: SlangAlgorithm,
+    pub length: usize
+}
+
+impl Default for SlangConfig {
+    fn default() -> Self {
+        SlangConfig { algorithm: SlangAlgorithm::Pronounceable, length: 11 }
+    }
+}
+
+fn pronounceable_hash(hash: &str) -> String {
     // Now parentheses from Muhammad Ikhwan Perwira
     // Source https://stackoverflow.com/questions/70912156/how-do-i-produce-spellable-hash-or-pronounceable-hash
 
@@ -20,9 +58,35 @@ fn pronounceable_hash(hash: &String) -> String {
         result.push(consonant[(x as usize) % LEN_CONS]);
       }
     }
-    return result.iter().collect();
+    result.iter().collect()
 }
 
-pub fn get_id_from_context(context: &String) -> String { base16ct::lower::encode_string(&Sha256::digest(context.as_bytes())) }
+pub fn get_id_from_context(context: &str) -> String { base16ct::lower::encode_string(&Sha256::digest(context.as_bytes())) }
+
+/// Id of content fed piecewise to `hasher`, equal to `get_id_from_context` over the whole content
+pub fn get_id_from_hasher(hasher: Sha256) -> String { base16ct::lower::encode_string(&hasher.finalize()) }
 
-pub fn get_slang_from_id(id: &String) -> String { pronounceable_hash(&(id[..11]).to_owned()) }
\ No newline at end of file
+/// Whether `id` has the form of a bucket id, a lowercase hex SHA-256 digest
+pub fn is_id(id: &str) -> bool {
+    id.len() == ID_LENGTH && id.bytes().all(|x| matches!(x, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+pub fn get_slang_from_id(id: &str, cfg: &SlangConfig) -> String {
+    match cfg.algorithm {
+        SlangAlgorithm::Pronounceable => pronounceable_hash(&id[..cfg.length]),
+        SlangAlgorithm::Hex => id[..cfg.length].to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_lowercase_sha256_digests() {
+        assert!(is_id(&get_id_from_context("hello")));
+        assert!(!is_id(&get_id_from_context("hello").to_uppercase()));
+        assert!(!is_id("abc"));
+        assert!(!is_id(&"é".repeat(32)));
+    }
+}
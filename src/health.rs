@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::redis::*;
+
+// probes must answer within the default Kubernetes probe timeout,
+// a pool busy for longer fails the probe while one busy for a moment does not
+const PING_TIMEOUT: Duration = Duration::from_millis(500);
+
+fn component(ok: bool, detail: String) -> Value {
+    json!({ "status": if ok { "ok" } else { "fail" }, "detail": detail })
+}
+
+fn check_redis(client: &RedisPool) -> (bool, String) {
+    let mut con = match client.get_timeout(PING_TIMEOUT) {
+        Ok(con) => con,
+        Err(e) => return (false, e.to_string())
+    };
+    match redis::cmd("PING").query::<String>(&mut *con) {
+        Ok(x) => (true, x),
+        Err(e) => (false, e.to_string())
+    }
+}
+
+fn check_schema(client: &RedisPool, ks: &KeySpace) -> (bool, String) {
+    match get_schema_version(client, ks) {
+        Ok(Some(x)) if (SCHEMA_VERSION_MIN..=SCHEMA_VERSION).contains(&x) => (true, format!("version {}", x)),
+        Ok(Some(x)) => (false, format!("version {} outside {} to {}", x, SCHEMA_VERSION_MIN, SCHEMA_VERSION)),
+        Ok(None) => (false, "version not stamped".to_string()),
+        Err(e) => (false, e.to_string())
+    }
+}
+
+/// Process is alive, without touching dependencies
+pub fn liveness() -> Value {
+    json!({ "status": "ok" })
+}
+
+/// Whether the instance can serve traffic, with the status of each component.
+/// A draining instance is never ready so load balancers stop routing to it before it stops accepting.
+pub fn readiness(client: &RedisPool, replica: Option<&RedisPool>, ks: &KeySpace, draining: bool) -> (bool, Value) {
+    if draining {
+        return (false, json!({
+            "status": "fail",
+            "components": { "shutdown": component(false, "draining in-flight requests".to_string()) }
+        }));
+    }
+    let (redis_ok, redis_detail) = check_redis(client);
+    // schema needs a connection, skip it rather than wait on an unreachable Redis
+    let (schema_ok, schema_detail) = if redis_ok { check_schema(client, ks) } else { (false, "Redis unreachable".to_string()) };
+
+    let mut ready = redis_ok && schema_ok;
+    let mut components = json!({
+        "redis": component(redis_ok, redis_detail),
+        "schema": component(schema_ok, schema_detail)
+    });
+    // queries fail without the replica, so it counts towards readiness
+    if let Some(replica) = replica {
+        let (replica_ok, replica_detail) = check_redis(replica);
+        ready = ready && replica_ok;
+        components["replica"] = component(replica_ok, replica_detail);
+    }
+    (ready, json!({
+        "status": if ready { "ok" } else { "fail" },
+        "components": components
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breaker::CircuitBreaker;
+    use crate::config::RedisConfig;
+
+    fn pool(address: &str) -> RedisPool {
+        let cfg = RedisConfig { address: address.to_string(), retries: 0, ..RedisConfig::default() };
+        redis_pool(redis_client(&cfg).unwrap(), 1, PING_TIMEOUT, CircuitBreaker::new("health", 0, Duration::ZERO))
+    }
+
+    fn keys() -> KeySpace { KeySpace { prefix: String::new(), namespace: DEFAULT_NAMESPACE.to_string() } }
+
+    #[test]
+    fn draining_never_ready() {
+        let (ready, body) = readiness(&pool("redis://127.0.0.1:1/"), None, &keys(), true);
+        assert!(!ready);
+        assert_eq!(body["components"]["shutdown"]["status"], "fail");
+        assert!(body["components"].get("redis").is_none());
+    }
+
+    #[test]
+    fn unreachable_redis_not_ready() {
+        let (ready, body) = readiness(&pool("redis://127.0.0.1:1/"), None, &keys(), false);
+        assert!(!ready);
+        assert_eq!(body["components"]["redis"]["status"], "fail");
+        assert_eq!(body["components"]["schema"]["detail"], "Redis unreachable");
+    }
+}
@@ -0,0 +1,14 @@
+pub mod breaker;
+pub mod chunk;
+pub mod codec;
+pub mod config;
+pub mod fsck;
+pub mod gql;
+pub mod health;
+pub mod hash;
+pub mod metrics;
+pub mod migrate;
+pub mod redis;
+pub mod search;
+pub mod sniff;
+pub mod tls;
@@ -1,26 +1,146 @@
-use std::{io, sync::Arc};
+use std::{
+    any::Any,
+    io,
+    process::ExitCode,
+    sync::{atomic::{AtomicBool, Ordering}, Arc},
+    time::{Duration, Instant},
+};
 
 use actix_cors::Cors;
 use actix_web::{
-    get, middleware, route,
+    dev::{Extensions, ServerHandle},
+    get, middleware, post, route,
+    http::header::{self, CacheControl, CacheDirective, ContentRange, ContentRangeSpec, ETag, EntityTag, Header, IfNoneMatch, IfRange, Range},
     web::{self, Data},
-    App, HttpResponse, HttpServer, Responder,
+    App, HttpRequest, HttpResponse, HttpServer, Responder,
 };
 use actix_web_lab::respond::Html;
+use clap::Parser;
+use futures_util::{stream, StreamExt};
 use juniper::http::{graphiql::graphiql_source, GraphQLRequest};
+use juniper::FieldError;
+use subtle::ConstantTimeEq;
+
+use bucket_jai::breaker::CircuitBreaker;
+use bucket_jai::config::{ConfigArgs, Configuration};
+use bucket_jai::chunk::{upload_tag, ChunkWriter};
+use bucket_jai::codec::Codec;
+use bucket_jai::gql::{check_quota, create_schema, download_bucket, download_id, operation_label, upload_bucket, write_upload, Content, Download, Schema};
+use bucket_jai::hash::get_id_from_context;
+use bucket_jai::sniff::{is_active, SNIFF_BYTES};
+use bucket_jai::tls::{server_config, ClientCert, ReloadingResolver};
+use bucket_jai::{health, metrics};
+use bucket_jai::redis::{check_schema_version, redis_client, redis_pool, KeySpace, RedisCtx, RedisPool, DEFAULT_NAMESPACE};
+
+const OWNER_ANONYMOUS: &str = "anonymous";
+
+#[derive(Parser)]
+#[command(version, about = "Object storage layer on Redis")]
+struct Cli {
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    /// Print the effective configuration as TOML and exit
+    #[arg(long)]
+    print_config: bool
+}
+
+fn request_api_key(req: &HttpRequest) -> Option<&str> {
+    req.headers().get("X-Api-Key").and_then(|v| v.to_str().ok()).filter(|v| !v.is_empty())
+}
+
+/// Owner of the request, derived from the `X-Api-Key` header so keys never land in Redis
+fn request_owner(req: &HttpRequest) -> String {
+    match request_api_key(req) {
+        Some(v) => get_id_from_context(v),
+        None => OWNER_ANONYMOUS.to_string()
+    }
+}
+
+// namespace named by `header`, confined to `owned` unless `admin` when namespaces follow owners
+fn select_namespace(header: Option<&str>, owned: Option<&str>, admin: bool) -> Option<String> {
+    let namespace = match (header, owned) {
+        (Some(x), Some(own)) if x != own && !admin => return None,
+        (Some(x), _) => x,
+        (None, Some(own)) => own,
+        (None, None) => DEFAULT_NAMESPACE
+    };
+    let valid = !namespace.is_empty() && namespace.len() <= 64
+        && namespace.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid { Some(namespace.to_string()) } else { None }
+}
+
+/// Namespace of the request, `X-Bucket-Namespace` header first then `default`.
+/// With `auth.namespace_by_owner` it is the owner's, only admins naming another one with the header.
+fn request_namespace(req: &HttpRequest, cfg: &Configuration) -> Option<String> {
+    let header = match req.headers().get("X-Bucket-Namespace") {
+        Some(v) => Some(v.to_str().ok()?),
+        None => None
+    };
+    let owned = match request_api_key(req) {
+        _ if !cfg.auth.namespace_by_owner => None,
+        Some(_) => Some(request_owner(req)),
+        None => Some(DEFAULT_NAMESPACE.to_string())
+    };
+    select_namespace(header, owned.as_deref(), request_admin(req, cfg))
+}
+
+/// Whether queries must read from the primary, set by clients reading right after their own mutation
+fn request_read_your_writes(req: &HttpRequest) -> bool {
+    req.headers().get("X-Read-Your-Writes").and_then(|v| v.to_str().ok()).is_some_and(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+}
+
+/// Whether `key` is the admin key, compared in constant time over digests so neither content nor length leaks through timing
+fn is_admin_key(key: &str, admin: &str) -> bool {
+    get_id_from_context(key).as_bytes().ct_eq(get_id_from_context(admin).as_bytes()).into()
+}
+
+/// Admin key holder, also presenting a verified client certificate when `tls.admin_require_client_cert` is set
+fn request_admin(req: &HttpRequest, cfg: &Configuration) -> bool {
+    let key = matches!((request_api_key(req), &cfg.auth.admin_api_key), (Some(k), Some(admin)) if is_admin_key(k, admin));
+    key && (!cfg.tls.admin_require_client_cert || req.conn_data::<ClientCert>().is_some())
+}
 
-mod gql;
-mod redis;
-mod hash;
+/// Records on the connection whether the TLS client presented a verified certificate
+fn tls_on_connect(conn: &dyn Any, data: &mut Extensions) {
+    if let Some(stream) = conn.downcast_ref::<actix_tls::accept::rustls_0_22::TlsStream<actix_web::rt::net::TcpStream>>() {
+        let (_, session) = stream.get_ref();
+        if session.peer_certificates().is_some_and(|x| !x.is_empty()) {
+            data.insert(ClientCert);
+        }
+    }
+}
 
-use crate::gql::{create_schema, Schema};
-use crate::redis::{redis_client, RedisCtx};
+/// Context of a GraphQL or REST request, `None` when its namespace is invalid
+fn request_context(req: &HttpRequest, cfg: &Configuration, pool: &RedisPool, replica: &Option<RedisPool>) -> Option<RedisCtx> {
+    let namespace = request_namespace(req, cfg)?;
+    let reader = match replica {
+        Some(x) if !request_read_your_writes(req) => x.clone(),
+        _ => pool.clone()
+    };
+    Some(RedisCtx {
+        client: pool.clone(),
+        reader,
+        keys: KeySpace { prefix: cfg.redis.key_prefix.to_owned(), namespace },
+        owner: request_owner(req),
+        quota: cfg.limits.quota(),
+        slang: cfg.slang.clone(),
+        storage: cfg.storage.clone(),
+        admin: request_admin(req, cfg)
+    })
+}
 
-#[derive(Clone)]
-struct Configuration {
-    redis_address: String,
-    server_address: String,
-    server_port: u16
+/// Whether a field of the GraphQL response failed because a Redis circuit breaker is open
+fn response_unavailable(body: &serde_json::Value) -> bool {
+    body["errors"].as_array().is_some_and(|x| x.iter().any(|e| e["extensions"]["internal_error"] == "SERVICE_UNAVAILABLE"))
+}
+
+/// Runs `f` on the blocking thread pool, Redis calls blocking their thread and sleeping between retries while Redis fails over
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> Result<T, HttpResponse> {
+    web::block(f).await.map_err(|e| {
+        log::error!("{}", e);
+        HttpResponse::InternalServerError().json(serde_json::json!({ "error": "BLOCKING" }))
+    })
 }
 
 /// GraphiQL playground UI
@@ -31,59 +151,612 @@ async fn graphql_playground() -> impl Responder {
 
 /// GraphQL endpoint
 #[route("/graphql", method = "GET", method = "POST")]
-async fn graphql(st: web::Data<Schema>, cfg: web::Data<Configuration>, data: web::Json<GraphQLRequest>) -> impl Responder {
-    if let Ok(_client) = redis_client(&cfg.redis_address) {
-        let ctx = RedisCtx {
-            client: _client
+async fn graphql(req: HttpRequest, st: web::Data<Schema>, cfg: web::Data<Configuration>, pool: web::Data<RedisPool>, replica: web::Data<Option<RedisPool>>, data: web::Json<GraphQLRequest>) -> impl Responder {
+    let ctx = match request_context(&req, &cfg, &pool, &replica) {
+        Some(v) => v,
+        None => return HttpResponse::BadRequest().body("Invalid namespace")
+    };
+    let start = Instant::now();
+    let operation = operation_label(&st, data.operation_name());
+    let (st, data) = (st.into_inner(), data.into_inner());
+    let (ok, body, ctx) = match blocking(move || {
+        let resp = data.execute_sync(&st, &ctx);
+        (resp.is_ok(), serde_json::to_value(&resp).unwrap_or_default(), ctx)
+    }).await {
+        Ok(v) => v,
+        Err(resp) => return resp
+    };
+    metrics::observe_graphql(&operation, ok, start);
+    // fields failed fast on an open breaker, tell clients when to come back
+    if response_unavailable(&body) {
+        let retry_after = ctx.client.breaker.retry_after().max(ctx.reader.breaker.retry_after());
+        return HttpResponse::ServiceUnavailable()
+            .insert_header(("Retry-After", retry_after.as_secs().max(1).to_string()))
+            .json(body);
+    }
+    HttpResponse::Ok().json(body)
+}
+
+/// REST error carrying the field error code, with the status the code stands for
+fn error_response(e: &FieldError, ctx: &RedisCtx) -> HttpResponse {
+    let code = e.message();
+    let mut resp = match code {
+        "NO_SLANG" | "NO_ID" => HttpResponse::NotFound(),
+        "QUOTA_EXCEEDED" => HttpResponse::Forbidden(),
+        "MIME_MISMATCH" => HttpResponse::UnsupportedMediaType(),
+        "SET_STR_K_CHUNK" => HttpResponse::ServiceUnavailable(),
+        "SERVICE_UNAVAILABLE" => {
+            let retry_after = ctx.client.breaker.retry_after().max(ctx.reader.breaker.retry_after());
+            let mut resp = HttpResponse::ServiceUnavailable();
+            resp.insert_header(("Retry-After", retry_after.as_secs().max(1).to_string()));
+            resp
+        },
+        _ => HttpResponse::InternalServerError()
+    };
+    resp.json(serde_json::json!({ "error": code }))
+}
+
+// drops the chunks of an upload that failed midway
+async fn abort_upload(writer: ChunkWriter) {
+    let _ = blocking(move || writer.abort()).await;
+}
+
+/// Streamed upload, the body being the content and `Content-Type` its mime, sniffed from the content when absent.
+/// Chunks are written as the body arrives, so memory stays bounded by the chunk size,
+/// and the quota is checked against `Content-Length` first and the size so far before each chunk.
+#[post("/buckets")]
+async fn upload(req: HttpRequest, cfg: web::Data<Configuration>, pool: web::Data<RedisPool>, replica: web::Data<Option<RedisPool>>, mut body: web::Payload) -> impl Responder {
+    let ctx = match request_context(&req, &cfg, &pool, &replica) {
+        Some(v) => v,
+        None => return HttpResponse::BadRequest().body("Invalid namespace")
+    };
+    let mime = req.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+    let length = req.headers().get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<usize>().ok());
+    if length.is_some_and(|x| x > cfg.limits.max_upload_bytes) {
+        return HttpResponse::PayloadTooLarge().json(serde_json::json!({ "error": "UPLOAD_TOO_LARGE" }));
+    }
+    let (checked, mut ctx) = match blocking(move || (check_quota(&ctx, length.unwrap_or(0)), ctx)).await {
+        Ok(v) => v,
+        Err(resp) => return resp
+    };
+    if let Err(e) = checked { return error_response(&e, &ctx) }
+
+    let chunk_bytes = cfg.storage.chunk_bytes;
+    let mut writer = ChunkWriter::new(&ctx.client, &ctx.keys, &upload_tag(), chunk_bytes);
+    let mut head = Vec::with_capacity(SNIFF_BYTES);
+    let mut pending = Vec::with_capacity(chunk_bytes);
+    loop {
+        let item = body.next().await;
+        let done = item.is_none();
+        if let Some(item) = item {
+            let bytes = match item {
+                Ok(v) => v,
+                Err(e) => {
+                    abort_upload(writer).await;
+                    return HttpResponse::BadRequest().body(e.to_string());
+                }
+            };
+            if writer.size() + pending.len() + bytes.len() > cfg.limits.max_upload_bytes {
+                abort_upload(writer).await;
+                return HttpResponse::PayloadTooLarge().json(serde_json::json!({ "error": "UPLOAD_TOO_LARGE" }));
+            }
+            head.extend_from_slice(&bytes[..bytes.len().min(SNIFF_BYTES - head.len())]);
+            pending.extend_from_slice(&bytes);
+        }
+        // whole chunks are written off the async workers
+        if pending.len() >= chunk_bytes || (done && !pending.is_empty()) {
+            let bytes = std::mem::replace(&mut pending, Vec::with_capacity(chunk_bytes));
+            let (written, w, c) = match blocking(move || (write_upload(&ctx, &mut writer, &bytes), writer, ctx)).await {
+                Ok(v) => v,
+                Err(resp) => return resp
+            };
+            (writer, ctx) = (w, c);
+            if let Err(e) = written {
+                abort_upload(writer).await;
+                return error_response(&e, &ctx);
+            }
+        }
+        if done { break }
+    }
+    let (created, ctx) = match blocking(move || (upload_bucket(&ctx, writer, mime, &head), ctx)).await {
+        Ok(v) => v,
+        Err(resp) => return resp
+    };
+    match created {
+        Ok(meta) => HttpResponse::Created().json(meta),
+        Err(e) => error_response(&e, &ctx)
+    }
+}
+
+/// First and last byte of the single range asked by `Range`, `None` for the whole content.
+/// Several ranges are answered with the whole content, as is a stale `If-Range`.
+fn request_range(req: &HttpRequest, id: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match Range::parse(req) {
+        Ok(Range::Bytes(x)) if x.len() == 1 => x[0].clone(),
+        _ => return Ok(None)
+    };
+    match IfRange::parse(req) {
+        Ok(IfRange::EntityTag(x)) if x.strong_eq(&EntityTag::new_strong(id.to_string())) => {},
+        Ok(_) => return Ok(None),
+        Err(_) if req.headers().contains_key(header::IF_RANGE) => return Ok(None),
+        Err(_) => {}
+    }
+    spec.to_satisfiable_range(size).map(Some).ok_or(())
+}
+
+/// Whether `If-None-Match` already holds the `"<id>"` entity tag of the content
+fn request_not_modified(req: &HttpRequest, id: &str) -> bool {
+    match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(x)) => x.iter().any(|x| x.weak_eq(&EntityTag::new_strong(id.to_string()))),
+        Err(_) => false
+    }
+}
+
+/// Whether `Accept-Encoding` lists `codec` with a non-zero quality
+fn request_accepts(req: &HttpRequest, codec: Codec) -> bool {
+    let name = codec.to_string();
+    req.headers().get_all(header::ACCEPT_ENCODING)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|x| {
+            let mut params = x.split(';');
+            let quality = params.clone().skip(1).find_map(|p| p.trim().strip_prefix("q=")).and_then(|q| q.parse::<f32>().ok()).unwrap_or(1.0);
+            params.next().is_some_and(|x| x.trim().eq_ignore_ascii_case(&name)) && quality > 0.0
+        })
+}
+
+/// Response carrying `found`, revalidated with its id as entity tag and cut to the requested range.
+/// Chunks are read from Redis as the client consumes them, only those covering the range.
+/// Compressed content is sent as is to clients accepting its codec, under its own entity tag, and decoded otherwise.
+fn content_response(req: &HttpRequest, found: Download, cache_control: CacheControl) -> HttpResponse {
+    let codec = match &found.content {
+        Content::Encoded(x, _) if !req.headers().contains_key(header::RANGE) && request_accepts(req, *x) => Some(*x),
+        _ => None
+    };
+    let tag = match codec { Some(x) => format!("{}-{}", found.id, x), None => found.id.to_owned() };
+    let etag = ETag(EntityTag::new_strong(tag.to_owned()));
+    // buckets are per namespace, a shared cache must not serve one namespace's content to another
+    let vary = (header::VARY, "X-Bucket-Namespace, Accept-Encoding");
+    if request_not_modified(req, &tag) {
+        return HttpResponse::NotModified().insert_header(etag).insert_header(cache_control).insert_header(vary).finish();
+    }
+    let content = match codec {
+        Some(_) => found.content,
+        None => match found.content.decode() {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("Cannot decode bucket {}: {}", found.id, e);
+                return HttpResponse::InternalServerError().json(serde_json::json!({ "error": "DECOMPRESS" }));
+            }
+        }
+    };
+    let size = found.size as u64;
+    let (mut resp, content) = match (codec, request_range(req, &found.id, size)) {
+        (Some(x), _) => {
+            let mut resp = HttpResponse::Ok();
+            resp.insert_header((header::CONTENT_ENCODING, x.to_string()));
+            (resp, content)
+        },
+        (None, Ok(None)) => {
+            let mut resp = HttpResponse::Ok();
+            resp.no_chunking(size);
+            (resp, content)
+        },
+        (None, Ok(Some((start, end)))) => {
+            let mut resp = HttpResponse::PartialContent();
+            resp.insert_header(ContentRange(ContentRangeSpec::Bytes { range: Some((start, end)), instance_length: Some(size) }))
+                .no_chunking(end - start + 1);
+            (resp, content.range(start as usize, end as usize + 1))
+        },
+        (None, Err(())) => return HttpResponse::RangeNotSatisfiable()
+            .insert_header(ContentRange(ContentRangeSpec::Bytes { range: None, instance_length: Some(size) }))
+            .finish()
+    };
+    // browsers must neither guess another type nor run active content in the origin of the API
+    let disposition = if found.sniffed_mime.is_some() || is_active(&found.mime) { "attachment" } else { "inline" };
+    resp.content_type(found.mime)
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .insert_header((header::CONTENT_DISPOSITION, disposition))
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header(etag)
+        .insert_header(cache_control)
+        .insert_header(vary);
+    match content {
+        Content::Data(x) | Content::Encoded(_, x) => resp.body(x),
+        Content::Chunks(reader) => resp.streaming(stream::unfold(reader, |mut reader| async move {
+            let (next, reader) = web::block(move || (reader.next(), reader)).await.ok()?;
+            Some((next?.map(web::Bytes::from).map_err(actix_web::error::ErrorServiceUnavailable), reader))
+        }))
+    }
+}
+
+/// Cache policy of downloads, kept out of shared caches when the namespace is derived from the API key
+fn download_cache_control(cfg: &Configuration, mut directives: Vec<CacheDirective>) -> CacheControl {
+    if cfg.auth.namespace_by_owner {
+        directives.retain(|x| *x != CacheDirective::Public);
+        directives.insert(0, CacheDirective::Private);
+    }
+    CacheControl(directives)
+}
+
+/// Streamed download of the content behind a slang.
+/// Slangs may be moved to other buckets, so caches revalidate with the entity tag.
+#[get("/buckets/{slang}")]
+async fn download(req: HttpRequest, slang: web::Path<String>, cfg: web::Data<Configuration>, pool: web::Data<RedisPool>, replica: web::Data<Option<RedisPool>>) -> impl Responder {
+    let ctx = match request_context(&req, &cfg, &pool, &replica) {
+        Some(v) => v,
+        None => return HttpResponse::BadRequest().body("Invalid namespace")
+    };
+    let (found, ctx) = match blocking(move || (download_bucket(&ctx, slang.into_inner()), ctx)).await {
+        Ok(v) => v,
+        Err(resp) => return resp
+    };
+    match found {
+        Ok(v) => content_response(&req, v, download_cache_control(&cfg, vec![CacheDirective::NoCache])),
+        Err(e) => error_response(&e, &ctx)
+    }
+}
+
+/// Streamed download of bucket `id`, whose content never changes and may be cached for good
+#[get("/id/{id}")]
+async fn download_by_id(req: HttpRequest, id: web::Path<String>, cfg: web::Data<Configuration>, pool: web::Data<RedisPool>, replica: web::Data<Option<RedisPool>>) -> impl Responder {
+    let ctx = match request_context(&req, &cfg, &pool, &replica) {
+        Some(v) => v,
+        None => return HttpResponse::BadRequest().body("Invalid namespace")
+    };
+    let (found, ctx) = match blocking(move || (download_id(&ctx, id.into_inner()), ctx)).await {
+        Ok(v) => v,
+        Err(resp) => return resp
+    };
+    match found {
+        Ok(v) => content_response(&req, v, download_cache_control(&cfg, vec![CacheDirective::Public, CacheDirective::MaxAge(31536000), CacheDirective::Extension("immutable".to_owned(), None)])),
+        Err(e) => error_response(&e, &ctx)
+    }
+}
+
+/// Prometheus metrics endpoint
+#[get("/metrics")]
+async fn prometheus_metrics(cfg: web::Data<Configuration>, pool: web::Data<RedisPool>) -> impl Responder {
+    let keys = KeySpace { prefix: cfg.redis.key_prefix.to_owned(), namespace: DEFAULT_NAMESPACE.to_string() };
+    let pool = pool.into_inner();
+    match blocking(move || metrics::refresh_storage(&pool, &keys)).await {
+        Ok(Err(e)) => log::warn!("cannot refresh storage metrics: {}", e),
+        Ok(Ok(())) => {},
+        Err(resp) => return resp
+    }
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::encode())
+}
+
+/// Liveness probe
+#[get("/healthz")]
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().json(health::liveness())
+}
+
+/// Readiness probe
+#[get("/readyz")]
+async fn readyz(cfg: web::Data<Configuration>, pool: web::Data<RedisPool>, replica: web::Data<Option<RedisPool>>, draining: web::Data<AtomicBool>) -> impl Responder {
+    let keys = KeySpace { prefix: cfg.redis.key_prefix.to_owned(), namespace: DEFAULT_NAMESPACE.to_string() };
+    let (pool, replica, draining) = (pool.into_inner(), replica.into_inner(), draining.load(Ordering::SeqCst));
+    let ready = match blocking(move || health::readiness(&pool, replica.as_ref().as_ref(), &keys, draining)).await {
+        Ok(v) => v,
+        Err(resp) => return resp
+    };
+    match ready {
+        (true, body) => HttpResponse::Ok().json(body),
+        (false, body) => HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("cannot listen for SIGTERM");
+        tokio::select! {
+            _ = terminate.recv() => {},
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Fails readiness on SIGTERM/SIGINT, waits for load balancers to notice, then drains in-flight requests
+async fn shutdown_on_signal(server: ServerHandle, draining: Arc<AtomicBool>, delay: Duration) {
+    wait_for_signal().await;
+    log::info!("shutdown signal received, failing readiness for {:?}", delay);
+    drain(server, draining, delay).await;
+}
+
+async fn drain(server: ServerHandle, draining: Arc<AtomicBool>, delay: Duration) {
+    draining.store(true, Ordering::SeqCst);
+    actix_web::rt::time::sleep(delay).await;
+
+    log::info!("draining in-flight requests");
+    server.stop(true).await;
+}
+
+/// Reloads the TLS certificate on SIGHUP, and when its files change every `interval`
+async fn reload_certificates(resolver: Arc<ReloadingResolver>, interval: Duration) {
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("cannot listen for SIGHUP");
+    loop {
+        let tick = async {
+            if interval.is_zero() { std::future::pending::<()>().await } else { actix_web::rt::time::sleep(interval).await }
+        };
+        #[cfg(unix)]
+        let force = tokio::select! {
+            _ = hangup.recv() => true,
+            _ = tick => false
         };
-        let resp = data.execute(&st, &ctx).await;
-        return HttpResponse::Ok().json(resp);
-    } else {
-        return HttpResponse::ServiceUnavailable().body("Redis service not available");
+        #[cfg(not(unix))]
+        let force = { tick.await; false };
+
+        if force { log::info!("SIGHUP received, reloading TLS certificate"); }
+        if let Err(e) = resolver.reload(force) {
+            log::error!("keeping current TLS certificate: {}", e);
+        }
     }
 }
 
-#[actix_web::main]
-async fn main() -> io::Result<()> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let config = match Configuration::load(&cli.config) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("configuration error: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+    if cli.print_config {
+        print!("{}", config.to_redacted_toml());
+        return ExitCode::SUCCESS;
+    }
+
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or(&config.logging.level));
+
+    match actix_web::rt::System::new().block_on(serve(config)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            log::error!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
 
+async fn serve(config: Configuration) -> io::Result<()> {
     // Create Juniper schema
     let schema = Arc::new(create_schema());
 
-    let config: Configuration = Configuration {
-        redis_address: match std::env::var_os("REDIS_ADDR") {
-            Some(v) => v.into_string().unwrap(),
-            None => "redis://127.0.0.1/".to_string()
-        },
-        server_address: match std::env::var_os("SERVER_ADDR") {
-            Some(v) => v.into_string().unwrap(),
-            None => "127.0.0.1".to_string()
-        },
-        server_port: match std::env::var_os("SERVER_PORT") {
-            Some(v) => v.into_string().unwrap().parse::<u16>().unwrap(),
-            None => 8080
+    let scheme = if config.tls.enabled() { "https" } else { "http" };
+    log::info!("starting HTTP server on port {}", config.server.port);
+    log::info!("GraphiQL playground: {}://{}:{}/graphiql", scheme, config.server.address, config.server.port);
+    log::info!("Redis address set to {}", config.redis.redacted_address());
+    if let Some(replica) = &config.redis.replica() {
+        log::info!("Redis read replica set to {}", replica.redacted_address());
+    }
+    log::info!("Redis key prefix set to \"{}\"", config.redis.key_prefix);
+    log::info!("Redis pool size set to {}", config.redis.pool_size);
+    log::info!("Quota per owner set to {:?} bytes, {:?} buckets", config.limits.quota_bytes, config.limits.quota_buckets);
+
+    // Refuse to serve a key layout this binary does not understand
+    let keys = KeySpace { prefix: config.redis.key_prefix.to_owned(), namespace: DEFAULT_NAMESPACE.to_string() };
+    let client = redis_client(&config.redis).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let breaker = CircuitBreaker::new("primary", config.redis.breaker_failures, config.redis.breaker_cooldown());
+    let pool = redis_pool(client, config.redis.pool_size, config.redis.pool_timeout(), breaker);
+    check_schema_version(&pool, &keys).map_err(io::Error::other)?;
+    // queries read from the replica when one is configured
+    let replica = match config.redis.replica() {
+        Some(cfg) => {
+            let client = redis_client(&cfg).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let breaker = CircuitBreaker::new("replica", cfg.breaker_failures, cfg.breaker_cooldown());
+            Some(redis_pool(client, cfg.pool_size, cfg.pool_timeout(), breaker))
         },
+        None => None
     };
 
-    log::info!("starting HTTP server on port {}", config.server_port);
-    log::info!("GraphiQL playground: http://{}:{}/graphiql", config.server_address, config.server_port);
-    log::info!("Redis address set to {}", config.redis_address);
+    // Certificates are served through a resolver so they can be swapped without a restart
+    let tls = match (&config.tls.cert_path, &config.tls.key_path) {
+        (Some(cert), Some(key)) => {
+            let resolver = Arc::new(ReloadingResolver::new(cert, key).map_err(io::Error::other)?);
+            let tls_config = server_config(&config.tls, resolver.clone()).map_err(io::Error::other)?;
+            log::info!("TLS enabled, client certificates {}", if config.tls.client_ca_path.is_some() { "verified" } else { "not requested" });
+            Some((resolver, tls_config))
+        },
+        _ => None
+    };
+    let reload_interval = Duration::from_secs(config.tls.reload_interval_secs);
 
-    let addr = config.server_address.to_owned();
-    let port = config.server_port;
+    let addr = config.server.address.to_owned();
+    let port = config.server.port;
+    let shutdown_delay = Duration::from_secs(config.server.shutdown_delay_secs);
+    let shutdown_timeout = config.server.shutdown_timeout_secs;
+    let json_config = web::JsonConfig::default().limit(config.limits.max_body_bytes);
+    let draining = Arc::new(AtomicBool::new(false));
+    let app_pool = pool.clone();
+    let replica = Arc::new(replica);
+    let app_replica = replica.clone();
+    let app_draining = draining.clone();
 
     // Start HTTP server
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(Data::from(schema.clone()))
             .app_data(Data::from(Arc::new(config.clone())))
+            .app_data(Data::new(app_pool.clone()))
+            .app_data(Data::from(app_replica.clone()))
+            .app_data(Data::from(app_draining.clone()))
+            .app_data(json_config.clone())
             .service(graphql)
             .service(graphql_playground)
+            .service(upload)
+            .service(download)
+            .service(download_by_id)
+            .service(prometheus_metrics)
+            .service(healthz)
+            .service(readyz)
             // the graphiql UI requires CORS to be enabled
             .wrap(Cors::permissive())
             .wrap(middleware::Logger::default())
     })
-    .bind((addr, port))?
-    .run()
-    .await
-}
\ No newline at end of file
+    // signals are handled below so readiness fails before the listener closes
+    .disable_signals()
+    .shutdown_timeout(shutdown_timeout)
+    .on_connect(tls_on_connect);
+
+    let server = match &tls {
+        Some((_, tls_config)) => server.bind_rustls_0_22((addr.to_owned(), port), tls_config.clone()),
+        None => server.bind((addr.to_owned(), port))
+    }
+    .map_err(|e| io::Error::new(e.kind(), format!("cannot bind {}:{}: {}", addr, port, e)))?
+    .run();
+
+    actix_web::rt::spawn(shutdown_on_signal(server.handle(), draining, shutdown_delay));
+    if let Some((resolver, _)) = tls {
+        actix_web::rt::spawn(reload_certificates(resolver, reload_interval));
+    }
+    server.await?;
+
+    // workers are gone, release pooled Redis connections before exit
+    log::info!("HTTP server stopped, closing Redis pools");
+    drop(pool);
+    drop(replica);
+    log::logger().flush();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downloads_private_when_namespace_follows_owner() {
+        let mut cfg = Configuration::default();
+        let immutable = || vec![CacheDirective::Public, CacheDirective::MaxAge(31536000)];
+        assert_eq!(download_cache_control(&cfg, immutable()).to_string(), "public, max-age=31536000");
+        cfg.auth.namespace_by_owner = true;
+        assert_eq!(download_cache_control(&cfg, immutable()).to_string(), "private, max-age=31536000");
+        assert_eq!(download_cache_control(&cfg, vec![CacheDirective::NoCache]).to_string(), "private, no-cache");
+    }
+
+    #[test]
+    fn namespace_header_or_default() {
+        assert_eq!(select_namespace(None, None, false).as_deref(), Some(DEFAULT_NAMESPACE));
+        assert_eq!(select_namespace(Some("team-a"), None, false).as_deref(), Some("team-a"));
+        assert_eq!(select_namespace(Some("a:b"), None, false), None);
+        assert_eq!(select_namespace(Some(""), None, false), None);
+        assert_eq!(select_namespace(Some(&"x".repeat(65)), None, false), None);
+    }
+
+    #[test]
+    fn owner_namespace_not_overridden() {
+        assert_eq!(select_namespace(None, Some("owner"), false).as_deref(), Some("owner"));
+        assert_eq!(select_namespace(Some("owner"), Some("owner"), false).as_deref(), Some("owner"));
+        assert_eq!(select_namespace(Some("other"), Some("owner"), false), None);
+        assert_eq!(select_namespace(Some(DEFAULT_NAMESPACE), Some("owner"), false), None);
+        assert_eq!(select_namespace(Some("other"), Some("owner"), true).as_deref(), Some("other"));
+    }
+
+    #[test]
+    fn admin_key_matched_exactly() {
+        assert!(is_admin_key("s3cret", "s3cret"));
+        assert!(!is_admin_key("s3cre", "s3cret"));
+        assert!(!is_admin_key("s3cret ", "s3cret"));
+        assert!(!is_admin_key("S3CRET", "s3cret"));
+
+        let mut cfg = Configuration::default();
+        assert!(!request_admin(&test_request(&[("X-Api-Key", "s3cret")]), &cfg));
+        cfg.auth.admin_api_key = Some("s3cret".to_string());
+        assert!(request_admin(&test_request(&[("X-Api-Key", "s3cret")]), &cfg));
+        assert!(!request_admin(&test_request(&[("X-Api-Key", "other")]), &cfg));
+        assert!(!request_admin(&test_request(&[]), &cfg));
+    }
+
+    fn test_request(headers: &[(&str, &str)]) -> HttpRequest {
+        headers.iter().fold(actix_web::test::TestRequest::default(), |r, x| r.insert_header(*x)).to_http_request()
+    }
+
+    #[test]
+    fn single_satisfiable_ranges_served() {
+        assert_eq!(request_range(&test_request(&[]), "id", 10), Ok(None));
+        assert_eq!(request_range(&test_request(&[("Range", "bytes=2-5")]), "id", 10), Ok(Some((2, 5))));
+        assert_eq!(request_range(&test_request(&[("Range", "bytes=-3")]), "id", 10), Ok(Some((7, 9))));
+        assert_eq!(request_range(&test_request(&[("Range", "bytes=8-")]), "id", 10), Ok(Some((8, 9))));
+        // past the end is unsatisfiable, several ranges are served whole
+        assert_eq!(request_range(&test_request(&[("Range", "bytes=10-")]), "id", 10), Err(()));
+        assert_eq!(request_range(&test_request(&[("Range", "bytes=0-1,4-5")]), "id", 10), Ok(None));
+    }
+
+    #[test]
+    fn ranges_only_of_matching_if_range() {
+        let range = ("Range", "bytes=0-1");
+        assert_eq!(request_range(&test_request(&[range, ("If-Range", "\"id\"")]), "id", 10), Ok(Some((0, 1))));
+        assert_eq!(request_range(&test_request(&[range, ("If-Range", "\"other\"")]), "id", 10), Ok(None));
+        assert_eq!(request_range(&test_request(&[range, ("If-Range", "W/\"id\"")]), "id", 10), Ok(None));
+        assert_eq!(request_range(&test_request(&[range, ("If-Range", "Sat, 17 Oct 2026 00:00:00 GMT")]), "id", 10), Ok(None));
+        assert_eq!(request_range(&test_request(&[range, ("If-Range", "garbage")]), "id", 10), Ok(None));
+    }
+
+    fn unconnected_pool(name: &str) -> RedisPool {
+        let client = redis_client(&bucket_jai::config::RedisConfig::default()).unwrap();
+        redis_pool(client, 1, Duration::from_millis(10), CircuitBreaker::new(name, 0, Duration::ZERO))
+    }
+
+    // names of the pools serving mutations, through `client`, and queries, through `reader`, of a request with `headers`
+    fn routed(headers: &[(&str, &str)], replica: &Option<RedisPool>) -> (&'static str, &'static str) {
+        let primary = unconnected_pool("primary");
+        let ctx = request_context(&test_request(headers), &Configuration::default(), &primary, replica).unwrap();
+        let name = |x: &RedisPool| if Arc::ptr_eq(&x.breaker, &primary.breaker) { "primary" } else { "replica" };
+        (name(&ctx.client), name(&ctx.reader))
+    }
+
+    #[test]
+    fn queries_read_from_replica_unless_reading_own_writes() {
+        let replica = Some(unconnected_pool("replica"));
+        assert_eq!(routed(&[], &replica), ("primary", "replica"));
+        assert_eq!(routed(&[("X-Read-Your-Writes", "1")], &replica), ("primary", "primary"));
+        assert_eq!(routed(&[("X-Read-Your-Writes", "TRUE")], &replica), ("primary", "primary"));
+        assert_eq!(routed(&[("X-Read-Your-Writes", "0")], &replica), ("primary", "replica"));
+        // without a replica everything goes to the primary
+        assert_eq!(routed(&[], &None), ("primary", "primary"));
+    }
+
+    #[actix_web::test]
+    async fn not_ready_once_draining() {
+        let draining = Arc::new(AtomicBool::new(false));
+        let app = actix_web::test::init_service(App::new()
+            .app_data(Data::new(Configuration::default()))
+            .app_data(Data::new(unconnected_pool("primary")))
+            .app_data(Data::new(None::<RedisPool>))
+            .app_data(Data::from(draining.clone()))
+            .service(readyz)).await;
+        let probe = || actix_web::test::TestRequest::get().uri("/readyz").to_request();
+
+        let body: serde_json::Value = actix_web::test::call_and_read_body_json(&app, probe()).await;
+        assert!(body["components"]["shutdown"].is_null());
+        draining.store(true, Ordering::SeqCst);
+        let resp = actix_web::test::call_service(&app, probe()).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = actix_web::test::read_body_json(resp).await;
+        assert_eq!(body["components"]["shutdown"]["status"], "fail");
+    }
+
+    #[actix_web::test]
+    async fn drain_fails_readiness_before_stopping() {
+        let server = HttpServer::new(App::new).workers(1).disable_signals().bind(("127.0.0.1", 0)).unwrap().run();
+        let draining = Arc::new(AtomicBool::new(false));
+        let stop = actix_web::rt::spawn(drain(server.handle(), draining.clone(), Duration::from_millis(200)));
+        let running = actix_web::rt::spawn(server);
+        actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+        assert!(draining.load(Ordering::SeqCst));
+        assert!(!running.is_finished());
+
+        stop.await.unwrap();
+        running.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+use crate::redis::*;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
+
+pub static REGISTRY: Lazy<Registry> = Lazy::new(|| Registry::new_custom(Some("bucket_jai".to_string()), None).unwrap());
+
+pub static GRAPHQL_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| register(IntCounterVec::new(
+    Opts::new("graphql_requests_total", "GraphQL requests by operation name and outcome"),
+    &["operation", "outcome"]
+).unwrap()));
+
+pub static GRAPHQL_DURATION: Lazy<HistogramVec> = Lazy::new(|| register(HistogramVec::new(
+    HistogramOpts::new("graphql_request_duration_seconds", "GraphQL request latency by operation name"),
+    &["operation"]
+).unwrap()));
+
+pub static RESOLVER_CALLS: Lazy<IntCounterVec> = Lazy::new(|| register(IntCounterVec::new(
+    Opts::new("resolver_calls_total", "Resolver calls by resolver and outcome"),
+    &["resolver", "outcome"]
+).unwrap()));
+
+pub static RESOLVER_DURATION: Lazy<HistogramVec> = Lazy::new(|| register(HistogramVec::new(
+    HistogramOpts::new("resolver_duration_seconds", "Resolver latency"),
+    &["resolver"]
+).unwrap()));
+
+pub static REDIS_DURATION: Lazy<HistogramVec> = Lazy::new(|| register(HistogramVec::new(
+    HistogramOpts::new("redis_command_duration_seconds", "Redis command latency")
+        .buckets(vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]),
+    &["command"]
+).unwrap()));
+
+pub static REDIS_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| register(IntCounterVec::new(
+    Opts::new("redis_command_errors_total", "Failed Redis commands"),
+    &["command"]
+).unwrap()));
+
+pub static REDIS_BREAKER_STATE: Lazy<IntGaugeVec> = Lazy::new(|| register(IntGaugeVec::new(
+    Opts::new("redis_breaker_state", "Redis circuit breaker state by pool, 0 closed, 1 open, 2 half-open"),
+    &["pool"]
+).unwrap()));
+
+pub static REDIS_BREAKER_TRANSITIONS: Lazy<IntCounterVec> = Lazy::new(|| register(IntCounterVec::new(
+    Opts::new("redis_breaker_transitions_total", "Redis circuit breaker transitions by pool and new state"),
+    &["pool", "state"]
+).unwrap()));
+
+pub static FIELD_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| register(IntCounterVec::new(
+    Opts::new("field_errors_total", "GraphQL field errors by error code"),
+    &["code"]
+).unwrap()));
+
+pub static STORED_BYTES: Lazy<IntGaugeVec> = Lazy::new(|| register(IntGaugeVec::new(
+    Opts::new("stored_bytes", "Bytes stored per namespace"),
+    &["namespace"]
+).unwrap()));
+
+pub static STORED_BUCKETS: Lazy<IntGaugeVec> = Lazy::new(|| register(IntGaugeVec::new(
+    Opts::new("stored_buckets", "Buckets stored per namespace"),
+    &["namespace"]
+).unwrap()));
+
+pub static SLANG_COLLISIONS: Lazy<IntCounter> = Lazy::new(|| register(IntCounter::new(
+    "slang_collisions_total", "Slangs requested while already pointing at another bucket"
+).unwrap()));
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(metric: T) -> T {
+    REGISTRY.register(Box::new(metric.clone())).unwrap();
+    metric
+}
+
+fn outcome<T, E>(result: &Result<T, E>) -> &'static str {
+    if result.is_ok() { "ok" } else { "error" }
+}
+
+/// Times a Redis command, counting it as failed when it returns an error
+pub fn observe_redis<T>(command: &str, f: impl FnOnce() -> redis::RedisResult<T>) -> redis::RedisResult<T> {
+    let start = Instant::now();
+    let result = f();
+    REDIS_DURATION.with_label_values(&[command]).observe(start.elapsed().as_secs_f64());
+    if result.is_err() { REDIS_ERRORS.with_label_values(&[command]).inc(); }
+    result
+}
+
+/// Times a GraphQL resolver and counts its outcome
+pub fn observe_resolver<T, E>(resolver: &str, f: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    let start = Instant::now();
+    let result = f();
+    RESOLVER_DURATION.with_label_values(&[resolver]).observe(start.elapsed().as_secs_f64());
+    RESOLVER_CALLS.with_label_values(&[resolver, outcome(&result)]).inc();
+    result
+}
+
+pub fn observe_graphql(operation: &str, ok: bool, start: Instant) {
+    GRAPHQL_DURATION.with_label_values(&[operation]).observe(start.elapsed().as_secs_f64());
+    GRAPHQL_REQUESTS.with_label_values(&[operation, if ok { "ok" } else { "error" }]).inc();
+}
+
+/// Refreshes stored bytes and buckets per namespace from the usage counters
+pub fn refresh_storage(client: &RedisPool, ks: &KeySpace) -> redis::RedisResult<()> {
+    let _ns = match get_set_kv(client, &set_k_namespaces(ks))? { Some(RedisRtn::Payloads(x)) => x, _ => Vec::new() };
+    for x in _ns.iter() {
+        for (field, gauge) in [(HASH_KF_BYTES, &STORED_BYTES), (HASH_KF_BUCKETS, &STORED_BUCKETS)] {
+            let value = match get_hash_kfv(client, &hash_k_namespace(ks, x), &field.to_string())? {
+                Some(RedisRtn::Payload(v)) => v.parse::<i64>().unwrap_or(0),
+                _ => 0
+            };
+            gauge.with_label_values(&[x]).set(value);
+        }
+    }
+    Ok(())
+}
+
+/// Metrics in Prometheus text exposition format
+pub fn encode() -> String {
+    // touch lazily registered metrics so they are exposed before first use
+    Lazy::force(&GRAPHQL_REQUESTS);
+    Lazy::force(&GRAPHQL_DURATION);
+    Lazy::force(&RESOLVER_CALLS);
+    Lazy::force(&RESOLVER_DURATION);
+    Lazy::force(&REDIS_DURATION);
+    Lazy::force(&REDIS_ERRORS);
+    Lazy::force(&REDIS_BREAKER_STATE);
+    Lazy::force(&REDIS_BREAKER_TRANSITIONS);
+    Lazy::force(&FIELD_ERRORS);
+    Lazy::force(&STORED_BYTES);
+    Lazy::force(&STORED_BUCKETS);
+    Lazy::force(&SLANG_COLLISIONS);
+
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+        log::error!("{}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
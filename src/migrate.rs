@@ -0,0 +1,158 @@
+use redis::Commands;
+
+use crate::redis::*;
+
+/// Step bringing the stored key layout to `version`, applied to every key matching `pattern`.
+/// `apply` must be idempotent as a resumed run may revisit keys, and returns whether the key needed a change.
+pub struct Migration {
+    pub version: isize,
+    pub description: &'static str,
+    pub pattern: fn(&KeySpace) -> String,
+    pub apply: fn(&mut RedisConnection, &KeySpace, &String, bool) -> redis::RedisResult<bool>
+}
+
+pub struct Options {
+    pub dry_run: bool,
+    pub batch: usize
+}
+
+const HASH_KF_VERSION : &str = "version";
+const HASH_KF_NODE : &str = "node";
+const HASH_KF_CURSOR : &str = "cursor";
+const HASH_KF_SCANNED : &str = "scanned";
+const HASH_KF_CHANGED : &str = "changed";
+
+pub fn migrations() -> Vec<Migration> {
+    vec![
+        Migration {
+            version: 2,
+            description: "backfill size and namespace usage of buckets created before quotas",
+            pattern: |ks| format!("{}*bkt:hash:id:*", ks.prefix),
+            apply: backfill_size
+        },
+        Migration {
+            version: 3,
+            description: "tag bucket hash and slang zset keys with the id so they share a cluster slot",
+            pattern: |ks| format!("{}*bkt:hash:id:*", ks.prefix),
+            apply: tag_bucket_keys
+        }
+    ]
+}
+
+fn backfill_size(con: &mut RedisConnection, ks: &KeySpace, key: &String, dry_run: bool) -> redis::RedisResult<bool> {
+    let exists : bool = con.hexists(key, HASH_KF_SIZE)?;
+    if exists { return Ok(false) }
+    if dry_run { return Ok(true) }
+
+    let size : isize = redis::cmd("HSTRLEN").arg(key).arg(HASH_KF_DATA).query(con)?;
+    let ns = namespace_of_key(ks, key);
+    // keys of different cluster slots, written one by one with the size first marking the bucket done,
+    // so a rerun after an interruption never counts a bucket twice
+    let _ : () = con.hset(key, HASH_KF_SIZE, size)?;
+    let _ : () = con.hincr(hash_k_namespace(ks, &ns), HASH_KF_BYTES, size)?;
+    let _ : () = con.hincr(hash_k_namespace(ks, &ns), HASH_KF_BUCKETS, 1)?;
+    let _ : () = con.sadd(set_k_namespaces(ks), &ns)?;
+    log::info!("Backfilled ([key] {}; [size] {}; [namespace] {})", key, size, ns);
+    Ok(true)
+}
+
+// DUMP and RESTORE rather than RENAME, which fails across cluster slots
+fn move_key(con: &mut RedisConnection, from: &String, to: &String) -> redis::RedisResult<bool> {
+    let dump : Option<Vec<u8>> = redis::cmd("DUMP").arg(from).query(con)?;
+    let dump = match dump { Some(x) => x, None => return Ok(false) };
+    let _ : () = redis::cmd("RESTORE").arg(to).arg(0).arg(dump).arg("REPLACE").query(con)?;
+    let _ : () = con.del(from)?;
+    Ok(true)
+}
+
+fn tag_bucket_keys(con: &mut RedisConnection, ks: &KeySpace, key: &String, dry_run: bool) -> redis::RedisResult<bool> {
+    if key.ends_with('}') { return Ok(false) }
+    let (head, id) = match key.rsplit_once("bkt:hash:id:") { Some(x) => x, None => return Ok(false) };
+    if dry_run { return Ok(true) }
+
+    let _ks = KeySpace { prefix: ks.prefix.to_owned(), namespace: namespace_of_key(ks, key) };
+    let _id = id.to_string();
+    // zset first, a rerun after an interruption still finds the untagged hash
+    move_key(con, &format!("{}slgs:zset:id:{}", head, id), &zset_k_id(&_ks, &_id))?;
+    move_key(con, key, &hash_k_id(&_ks, &_id))?;
+    log::info!("Tagged ([key] {}; [namespace] {})", key, _ks.namespace);
+    Ok(true)
+}
+
+fn get_progress(con: &mut RedisConnection, ks: &KeySpace, field: &str) -> redis::RedisResult<u64> {
+    let value : Option<u64> = con.hget(hash_k_migration(ks), field)?;
+    Ok(value.unwrap_or(0))
+}
+
+fn apply_migration(con: &mut RedisConnection, ks: &KeySpace, m: &Migration, opts: &Options) -> redis::RedisResult<()> {
+    // a cluster is scanned one primary after the other
+    let nodes = scan_nodes(con)?;
+
+    // resume from the recorded node and cursor when this migration was interrupted
+    let (mut first, mut cursor, mut scanned, mut changed) = (0, 0, 0, 0);
+    if get_progress(con, ks, HASH_KF_VERSION)? == m.version as u64 {
+        let node : Option<String> = con.hget(hash_k_migration(ks), HASH_KF_NODE)?;
+        match nodes.iter().position(|x| Some(x) == node.as_ref()) {
+            Some(i) => {
+                first = i;
+                cursor = get_progress(con, ks, HASH_KF_CURSOR)?;
+                log::info!("Resuming migration {} at node \"{}\" cursor {}", m.version, nodes[i], cursor);
+            },
+            None => log::warn!("Node of interrupted migration {} is gone, restarting the scan", m.version)
+        }
+        scanned = get_progress(con, ks, HASH_KF_SCANNED)?;
+        changed = get_progress(con, ks, HASH_KF_CHANGED)?;
+    }
+
+    for (i, node) in nodes.iter().enumerate().skip(first) {
+        loop {
+            let (next, keys) = scan_page(con, node, cursor, &(m.pattern)(ks), opts.batch)?;
+            for key in keys.iter() {
+                if (m.apply)(con, ks, key, opts.dry_run)? { changed += 1; }
+            }
+            scanned += keys.len() as u64;
+            cursor = next;
+
+            // a finished node is recorded as the start of the next one
+            let (at_node, at_cursor) = match (cursor, nodes.get(i + 1)) {
+                (0, Some(x)) => (x, 0),
+                _ => (node, cursor)
+            };
+            if !opts.dry_run {
+                let _ : () = con.hset_multiple(hash_k_migration(ks), &[
+                    (HASH_KF_VERSION, m.version.to_string()),
+                    (HASH_KF_NODE, at_node.to_owned()),
+                    (HASH_KF_CURSOR, at_cursor.to_string()),
+                    (HASH_KF_SCANNED, scanned.to_string()),
+                    (HASH_KF_CHANGED, changed.to_string())
+                ])?;
+            }
+            log::info!("Migration {} progress ([node] \"{}\"; [cursor] {}; [scanned] {}; [changed] {})", m.version, node, cursor, scanned, changed);
+            if cursor == 0 { break; }
+        }
+    }
+
+    if opts.dry_run {
+        log::info!("Migration {} would change {} of {} keys", m.version, changed, scanned);
+    } else {
+        let _ : () = con.set(str_k_schema(ks), m.version)?;
+        let _ : () = con.del(hash_k_migration(ks))?;
+        log::info!("Migration {} done, changed {} of {} keys", m.version, changed, scanned);
+    }
+    Ok(())
+}
+
+/// Applies every migration newer than the stored schema version, in order
+pub fn run(client: &RedisPool, ks: &KeySpace, opts: &Options) -> redis::RedisResult<isize> {
+    let stored = get_schema_version(client, ks)?.unwrap_or(SCHEMA_VERSION_LEGACY);
+    let mut con = get_connection(client)?;
+    log::info!("Stored schema version {}, latest {}", stored, SCHEMA_VERSION);
+
+    let mut version = stored;
+    for m in migrations().iter().filter(|m| m.version > stored) {
+        log::info!("Applying migration {}: {}{}", m.version, m.description, if opts.dry_run { " (dry run)" } else { "" });
+        apply_migration(&mut con, ks, m, opts)?;
+        if !opts.dry_run { version = m.version; }
+    }
+    Ok(version)
+}
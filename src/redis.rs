@@ -1,11 +1,77 @@
-use redis::Commands;
+use std::{collections::{BTreeMap, HashMap}, ops::Deref, path::Path, sync::{Arc, RwLock}, time::Duration};
+
+use redis::{Commands, ConnectionLike, IntoConnectionInfo};
+
+use crate::breaker::CircuitBreaker;
+use crate::config::{RedisConfig, StorageConfig};
+use crate::hash::SlangConfig;
+use crate::metrics::observe_redis;
 
 pub const HASH_KF_MIME : &str = "mime";
 pub const HASH_KF_DATA : &str = "data";
 pub const HASH_KF_RSA : &str = "rsa";
+pub const HASH_KF_OWNER : &str = "owner";
+pub const HASH_KF_SIZE : &str = "size";
+pub const HASH_KF_CHUNKS : &str = "chunks";           // number of chunks, absent for inline data
+pub const HASH_KF_CHUNK_BYTES : &str = "chunk_bytes"; // size of each chunk but the last
+pub const HASH_KF_CHUNK_TAG : &str = "chunk_tag";     // tag of the chunk keys
+pub const HASH_KF_ENCODING : &str = "encoding";       // codec of inline data, absent when stored as sent
+pub const HASH_KF_SNIFFED_MIME : &str = "sniffed_mime"; // mime sniffed from content contradicting the declared one
+pub const HASH_KF_CREATED_AT : &str = "created_at";   // unix milliseconds, as are the other timestamps
+pub const HASH_KF_UPDATED_AT : &str = "updated_at";
+pub const HASH_KF_ACCESSED_AT : &str = "accessed_at";
+pub const HASH_KF_READS : &str = "reads";
+
+pub const HASH_KF_BYTES : &str = "bytes";
+pub const HASH_KF_BUCKETS : &str = "buckets";
+
+/// Storage limits applied per owner, `None` being unlimited
+#[derive(Clone, Default)]
+pub struct Quota {
+    pub bytes: Option<isize>,
+    pub buckets: Option<isize>
+}
+
+pub const DEFAULT_NAMESPACE : &str = "default";
+
+/// Version of the key layout below, bumped whenever stored data must be rewritten
+pub const SCHEMA_VERSION : isize = 3;
+/// Oldest version still served, so `bucket-jai-migrate` can run while the server is online.
+/// Version 3 renamed bucket keys, older layouts must be migrated before the server starts.
+pub const SCHEMA_VERSION_MIN : isize = 3;
+/// Version of data written before versions were stamped
+pub const SCHEMA_VERSION_LEGACY : isize = 1;
+
+/// Key space a request operates in, isolating deployments by prefix and slangs and buckets per tenant
+#[derive(Clone)]
+pub struct KeySpace {
+    pub prefix: String,
+    pub namespace: String
+}
+
+/// Connection pool of a `RedisClient` with its retry policy, calls failing fast while its circuit breaker is open
+#[derive(Clone)]
+pub struct RedisPool {
+    pool: r2d2::Pool<RedisClient>,
+    retry: Retry,
+    pub breaker: Arc<CircuitBreaker>
+}
+
+impl Deref for RedisPool {
+    type Target = r2d2::Pool<RedisClient>;
+
+    fn deref(&self) -> &Self::Target { &self.pool }
+}
 
 pub struct RedisCtx {
-    pub client: redis::Client
+    pub client: RedisPool,
+    pub reader: RedisPool,  // pool serving queries, the replica unless the request asked to read its writes
+    pub keys: KeySpace,
+    pub owner: String,
+    pub quota: Quota,
+    pub slang: SlangConfig,
+    pub storage: StorageConfig,
+    pub admin: bool
 }
 
 pub enum Status { Int(isize), String(String) }
@@ -16,20 +82,450 @@ pub enum RedisRtn {
     Payloads(Vec<String>)
 }
 
-pub fn str_k_slang(s : &String) -> String { format!("idx:str:slg:{}", s) }
+// default namespace keeps the unprefixed keys so existing data stays reachable
+fn ns_prefix(ks : &KeySpace) -> String {
+    if ks.namespace == DEFAULT_NAMESPACE { ks.prefix.to_owned() } else { format!("{}ns:{}:", ks.prefix, ks.namespace) }
+}
+
+pub fn str_k_slang(ks : &KeySpace, s : &String) -> String { format!("{}idx:str:slg:{}", ns_prefix(ks), s) }
+
+// keys of one bucket carry its id as hash tag so they share a Redis Cluster slot
+pub fn hash_k_id(ks : &KeySpace, s : &String) -> String { format!("{}bkt:hash:id:{{{}}}", ns_prefix(ks), s) }
+
+pub fn zset_k_id(ks : &KeySpace, s : &String) -> String { format!("{}slgs:zset:id:{{{}}}", ns_prefix(ks), s) }
+
+pub fn zset_k_created(ks : &KeySpace, s : &String) -> String { format!("{}slgs:zset:created:{{{}}}", ns_prefix(ks), s) }
+
+pub fn hash_k_labels(ks : &KeySpace, s : &String) -> String { format!("{}bkt:hash:labels:{{{}}}", ns_prefix(ks), s) }
+
+// reverse index of a label, `s` being `key=value`
+pub fn set_k_label(ks : &KeySpace, s : &String) -> String { format!("{}lbl:set:{}", ns_prefix(ks), s) }
+
+// terms indexed for bucket `s`, dropped with it
+pub fn set_k_terms(ks : &KeySpace, s : &String) -> String { format!("{}fts:set:id:{{{}}}", ns_prefix(ks), s) }
+
+// buckets whose content holds term `s`
+pub fn set_k_term(ks : &KeySpace, s : &String) -> String { format!("{}fts:set:term:{}", ns_prefix(ks), s) }
+
+// chunks of one payload share its tag so they share a Redis Cluster slot
+pub fn str_k_chunk(ks : &KeySpace, tag : &String, n : usize) -> String { format!("{}bkt:chunk:tag:{{{}}}:{}", ns_prefix(ks), tag, n) }
+
+// tags of chunks still being written, scored with the time of their last chunk
+pub fn zset_k_uploads(ks : &KeySpace) -> String { format!("{}bkt:zset:uploads", ns_prefix(ks)) }
+
+pub fn hash_k_owner(ks : &KeySpace, s : &String) -> String { format!("{}qta:hash:owner:{}", ks.prefix, s) }
+
+pub fn hash_k_namespace(ks : &KeySpace, s : &String) -> String { format!("{}nss:hash:ns:{}", ks.prefix, s) }
+
+pub fn set_k_namespaces(ks : &KeySpace) -> String { format!("{}nss:set:all", ks.prefix) }
+
+pub fn str_k_schema(ks : &KeySpace) -> String { format!("{}meta:str:schema", ks.prefix) }
+
+pub fn hash_k_migration(ks : &KeySpace) -> String { format!("{}meta:hash:migration", ks.prefix) }
+
+/// Namespace of a bucket key found by scanning, inverse of `hash_k_id`
+pub fn namespace_of_key(ks : &KeySpace, key : &str) -> String {
+    let _rest = key.strip_prefix(ks.prefix.as_str()).unwrap_or(key);
+    match _rest.strip_prefix("ns:").and_then(|x| x.split_once(':')) {
+        Some((ns, _)) => ns.to_string(),
+        None => DEFAULT_NAMESPACE.to_string()
+    }
+}
+
+fn read_pem(path: &Path) -> redis::RedisResult<Vec<u8>> {
+    std::fs::read(path).map_err(|e| redis::RedisError::from((redis::ErrorKind::InvalidClientConfig, "cannot read TLS file", format!("{}: {}", path.display(), e))))
+}
+
+fn tls_certificates(cfg: &RedisConfig) -> redis::RedisResult<Option<redis::TlsCertificates>> {
+    if cfg.tls_ca_path.is_none() && cfg.tls_cert_path.is_none() { return Ok(None) }
+    let client_tls = match (&cfg.tls_cert_path, &cfg.tls_key_path) {
+        (Some(cert), Some(key)) => Some(redis::ClientTlsConfig { client_cert: read_pem(cert)?, client_key: read_pem(key)? }),
+        _ => None
+    };
+    let root_cert = match &cfg.tls_ca_path {
+        Some(path) => Some(read_pem(path)?),
+        None => None
+    };
+    Ok(Some(redis::TlsCertificates { client_tls, root_cert }))
+}
+
+// single node client for `addr` with the configured ACL credentials and TLS certificates
+fn node_client(cfg: &RedisConfig, addr: &str, certs: &Option<redis::TlsCertificates>) -> redis::RedisResult<redis::Client> {
+    let mut info = addr.into_connection_info()?;
+    if cfg.username.is_some() { info.redis.username = cfg.username.clone(); }
+    let password = cfg.password()
+        .map_err(|e| redis::RedisError::from((redis::ErrorKind::InvalidClientConfig, "cannot read Redis password", e.to_string())))?;
+    if password.is_some() { info.redis.password = password; }
+
+    match certs {
+        Some(x) => redis::Client::build_with_tls(info, x.clone()),
+        None => redis::Client::open(info)
+    }
+}
 
-pub fn hash_k_id(s : &String) -> String { format!("bkt:hash:id:{}", s) }
+// connection settings of `node` pointed at `addr`, a `host:port` reported by the cluster or a sentinel
+fn node_at(node: &redis::ConnectionInfo, addr: &str) -> redis::RedisResult<redis::ConnectionInfo> {
+    let (host, port) = addr.rsplit_once(':')
+        .and_then(|(h, p)| p.parse::<u16>().ok().map(|p| (h.to_string(), p)))
+        .ok_or_else(|| redis::RedisError::from((redis::ErrorKind::ClientError, "invalid node address", addr.to_string())))?;
+    let mut info = node.clone();
+    info.addr = match &node.addr {
+        redis::ConnectionAddr::TcpTls { insecure, tls_params, .. } => redis::ConnectionAddr::TcpTls { host, port, insecure: *insecure, tls_params: tls_params.clone() },
+        _ => redis::ConnectionAddr::Tcp(host, port)
+    };
+    Ok(info)
+}
 
-pub fn zset_k_id(s : &String) -> String { format!("slgs:zset:id:{}", s) }
+/// Attempts and first delay, doubled on each attempt up to `max_backoff`, for commands failing while Redis fails over
+#[derive(Clone, Copy)]
+pub struct Retry {
+    pub attempts: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration
+}
 
-pub fn redis_client(redis_addr: &String) -> Result<redis::Client, redis::RedisError> {
-    Ok(redis::Client::open(redis_addr.to_owned())?)
+// master of a Sentinel deployment, `master` caching the last discovered `host:port`
+struct SentinelTarget {
+    sentinels: Vec<redis::Client>,
+    master_name: String,
+    node: redis::ConnectionInfo,
+    master: Arc<RwLock<Option<String>>>
 }
 
-pub fn get_kv(client: &redis::Client, key: &String) -> redis::RedisResult<Option<RedisRtn>> {
-    let mut con = client.get_connection()?;
+impl SentinelTarget {
+    // first answer of the sentinels, in configured order
+    fn discover(&self) -> redis::RedisResult<String> {
+        let mut last_error = None;
+        for sentinel in self.sentinels.iter() {
+            let found = sentinel.get_connection_with_timeout(SENTINEL_TIMEOUT).and_then(|mut con| {
+                redis::cmd("SENTINEL").arg("get-master-addr-by-name").arg(&self.master_name).query::<Option<(String, u16)>>(&mut con)
+            });
+            match found {
+                Ok(Some((host, port))) => {
+                    let addr = format!("{}:{}", host, port);
+                    if let Ok(mut master) = self.master.write() {
+                        if master.as_ref() != Some(&addr) { log::info!("Redis master {} discovered at {}", self.master_name, addr); }
+                        *master = Some(addr.to_owned());
+                    }
+                    return Ok(addr);
+                },
+                Ok(None) => log::warn!("Sentinel {} does not know master {}", sentinel.get_connection_info().addr, self.master_name),
+                Err(e) => {
+                    log::warn!("Sentinel {} unreachable: {}", sentinel.get_connection_info().addr, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| redis::RedisError::from((redis::ErrorKind::MasterDown, "no sentinel knows master", self.master_name.to_owned()))))
+    }
+
+    // connection to `addr`, refused unless it still is the master
+    fn connect_master(&self, addr: &str) -> redis::RedisResult<redis::Connection> {
+        let mut con = redis::Client::open(node_at(&self.node, addr)?)?.get_connection()?;
+        let role : Vec<redis::Value> = redis::cmd("ROLE").query(&mut con)?;
+        match role.first().map(redis::from_redis_value::<String>) {
+            Some(Ok(x)) if x == "master" => Ok(con),
+            _ => Err(redis::RedisError::from((redis::ErrorKind::ReadOnly, "node is no longer master", addr.to_string())))
+        }
+    }
+
+    fn forget(&self, addr: &str) {
+        if let Ok(mut master) = self.master.write() {
+            if master.as_deref() == Some(addr) { *master = None; }
+        }
+    }
+
+    fn current(&self) -> Option<String> {
+        self.master.read().ok().and_then(|x| x.clone())
+    }
+
+    fn connect(&self) -> redis::RedisResult<(redis::Connection, String)> {
+        if let Some(addr) = self.current() {
+            match self.connect_master(&addr) {
+                Ok(con) => return Ok((con, addr)),
+                Err(e) => {
+                    log::warn!("Redis master {} at {} unavailable, asking sentinels: {}", self.master_name, addr, e);
+                    self.forget(&addr);
+                }
+            }
+        }
+        let addr = self.discover()?;
+        Ok((self.connect_master(&addr)?, addr))
+    }
+}
+
+const SENTINEL_TIMEOUT : Duration = Duration::from_millis(500);
+
+enum Target {
+    Single(redis::Client),
+    Cluster { client: Box<redis::cluster::ClusterClient>, node: Box<redis::ConnectionInfo> },
+    Sentinel(Box<SentinelTarget>)
+}
+
+/// Single Redis, Redis Cluster or master behind Redis Sentinel, with the retry policy of its connections
+pub struct RedisClient {
+    target: Target,
+    retry: Retry
+}
+
+enum Link {
+    Single(redis::Connection),
+    Cluster { con: Box<redis::cluster::ClusterConnection>, node: Box<redis::ConnectionInfo>, primaries: HashMap<String, redis::Connection> },
+    Sentinel { con: redis::Connection, master: Arc<RwLock<Option<String>>>, addr: String }
+}
+
+/// Connection of a `RedisClient`. Cluster connections route each command by key slot,
+/// and open direct connections to primaries on demand for commands that cannot be routed such as SCAN.
+/// Sentinel connections go stale once another master is discovered, so the pool replaces them.
+pub struct RedisConnection {
+    link: Link,
+    broken: bool
+}
+
+impl RedisConnection {
+    fn con(&mut self) -> &mut dyn ConnectionLike {
+        match &mut self.link {
+            Link::Single(con) | Link::Sentinel { con, .. } => con,
+            Link::Cluster { con, .. } => con.as_mut()
+        }
+    }
+
+    fn con_ref(&self) -> &dyn ConnectionLike {
+        match &self.link {
+            Link::Single(con) | Link::Sentinel { con, .. } => con,
+            Link::Cluster { con, .. } => con.as_ref()
+        }
+    }
+
+    fn is_stale(&self) -> bool {
+        match &self.link {
+            Link::Sentinel { master, addr, .. } => master.read().map(|x| x.as_ref() != Some(addr)).unwrap_or(true),
+            _ => false
+        }
+    }
+
+    // drops the connection from the pool, and the master from the sentinel cache
+    fn failed(&mut self) {
+        self.broken = true;
+        if let Link::Sentinel { master, addr, .. } = &self.link {
+            if let Ok(mut x) = master.write() {
+                if x.as_ref() == Some(addr) { *x = None; }
+            }
+        }
+    }
+}
+
+/// Client for `cfg.address`, the cluster seeded by `cfg.cluster_nodes` or the master behind `cfg.sentinels`,
+/// with the configured ACL credentials and TLS certificates
+pub fn redis_client(cfg: &RedisConfig) -> redis::RedisResult<RedisClient> {
+    let certs = tls_certificates(cfg)?;
+    let retry = Retry { attempts: cfg.retries, backoff: cfg.retry_backoff(), max_backoff: cfg.retry_backoff_max() };
+
+    if !cfg.cluster_nodes.is_empty() {
+        let nodes = cfg.cluster_nodes.iter()
+            .map(|x| node_client(cfg, x, &certs).map(|c| c.get_connection_info().clone()))
+            .collect::<redis::RedisResult<Vec<_>>>()?;
+        let mut builder = redis::cluster::ClusterClientBuilder::new(nodes.clone());
+        if let Some(x) = certs { builder = builder.certs(x); }
+        let target = Target::Cluster { client: Box::new(builder.build()?), node: Box::new(nodes[0].clone()) };
+        return Ok(RedisClient { target, retry });
+    }
+    if !cfg.sentinels.is_empty() {
+        // sentinels keep the credentials of their own URL, the master those of `address`
+        let sentinels = cfg.sentinels.iter()
+            .map(|x| match &certs {
+                Some(c) => redis::Client::build_with_tls(x.as_str(), c.clone()),
+                None => redis::Client::open(x.as_str())
+            })
+            .collect::<redis::RedisResult<Vec<_>>>()?;
+        let node = node_client(cfg, &cfg.address, &certs)?.get_connection_info().clone();
+        let target = Target::Sentinel(Box::new(SentinelTarget {
+            sentinels,
+            master_name: cfg.sentinel_master.to_owned(),
+            node,
+            master: Arc::new(RwLock::new(None))
+        }));
+        return Ok(RedisClient { target, retry });
+    }
+    Ok(RedisClient { target: Target::Single(node_client(cfg, &cfg.address, &certs)?), retry })
+}
+
+impl r2d2::ManageConnection for RedisClient {
+    type Connection = RedisConnection;
+    type Error = redis::RedisError;
+
+    fn connect(&self) -> redis::RedisResult<RedisConnection> {
+        let link = match &self.target {
+            Target::Single(client) => Link::Single(client.get_connection()?),
+            Target::Cluster { client, node } => Link::Cluster {
+                con: Box::new(client.get_connection()?),
+                node: node.clone(),
+                primaries: HashMap::new()
+            },
+            Target::Sentinel(target) => {
+                let (con, addr) = target.connect()?;
+                Link::Sentinel { con, master: target.master.clone(), addr }
+            }
+        };
+        Ok(RedisConnection { link, broken: false })
+    }
+
+    fn is_valid(&self, con: &mut RedisConnection) -> redis::RedisResult<()> {
+        if !con.broken && !con.is_stale() && con.check_connection() { Ok(()) } else { Err(redis::RedisError::from(std::io::Error::from(std::io::ErrorKind::BrokenPipe))) }
+    }
+
+    fn has_broken(&self, con: &mut RedisConnection) -> bool { con.broken || con.is_stale() || !con.is_open() }
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> redis::RedisResult<redis::Value> { self.con().req_packed_command(cmd) }
+
+    fn req_packed_commands(&mut self, cmd: &[u8], offset: usize, count: usize) -> redis::RedisResult<Vec<redis::Value>> {
+        self.con().req_packed_commands(cmd, offset, count)
+    }
+
+    fn req_command(&mut self, cmd: &redis::Cmd) -> redis::RedisResult<redis::Value> { self.con().req_command(cmd) }
+
+    fn get_db(&self) -> i64 { self.con_ref().get_db() }
+
+    fn supports_pipelining(&self) -> bool { self.con_ref().supports_pipelining() }
+
+    fn check_connection(&mut self) -> bool { self.con().check_connection() }
+
+    fn is_open(&self) -> bool { self.con_ref().is_open() }
+}
+
+// `host:port` of every primary from `CLUSTER SLOTS`, each entry being [start, end, [host, port, ..], replicas..]
+fn cluster_primaries(con: &mut redis::cluster::ClusterConnection) -> redis::RedisResult<Vec<String>> {
+    let slots : Vec<Vec<redis::Value>> = redis::cmd("CLUSTER").arg("SLOTS").query(con)?;
+    let mut primaries = Vec::new();
+    for x in slots.iter() {
+        if let Some(primary) = x.get(2) {
+            let (host, port) : (String, u16) = match primary {
+                redis::Value::Bulk(v) if v.len() >= 2 => (redis::from_redis_value(&v[0])?, redis::from_redis_value(&v[1])?),
+                _ => continue
+            };
+            primaries.push(format!("{}:{}", host, port));
+        }
+    }
+    primaries.sort();
+    primaries.dedup();
+    Ok(primaries)
+}
+
+/// Nodes to SCAN for every key, the only node of a single Redis or master, or each cluster primary
+pub fn scan_nodes(con: &mut RedisConnection) -> redis::RedisResult<Vec<String>> {
+    match &mut con.link {
+        Link::Cluster { con, .. } => cluster_primaries(con),
+        _ => Ok(vec![String::new()])
+    }
+}
+
+/// One SCAN page on `node` as listed by `scan_nodes`
+pub fn scan_page(con: &mut RedisConnection, node: &str, cursor: u64, pattern: &str, count: usize) -> redis::RedisResult<(u64, Vec<String>)> {
+    let mut scan = redis::cmd("SCAN");
+    scan.arg(cursor).arg("MATCH").arg(pattern).arg("COUNT").arg(count);
+    match &mut con.link {
+        Link::Cluster { node: template, primaries, .. } => {
+            if !primaries.contains_key(node) {
+                let client = redis::Client::open(node_at(template, node)?)?;
+                primaries.insert(node.to_string(), client.get_connection()?);
+            }
+            match primaries.get_mut(node) {
+                Some(x) => scan.query(x),
+                None => Err(redis::RedisError::from((redis::ErrorKind::ClientError, "primary unavailable", node.to_string())))
+            }
+        },
+        Link::Single(con) | Link::Sentinel { con, .. } => scan.query(con)
+    }
+}
+
+/// Calls `f` with every key matching `pattern` on every node
+pub fn scan_each(con: &mut RedisConnection, pattern: &str, count: usize, f: &mut dyn FnMut(&mut RedisConnection, &String) -> redis::RedisResult<()>) -> redis::RedisResult<()> {
+    for node in scan_nodes(con)?.iter() {
+        let mut cursor : u64 = 0;
+        loop {
+            let (next, keys) = scan_page(con, node, cursor, pattern, count)?;
+            for key in keys.iter() { f(con, key)?; }
+            cursor = next;
+            if cursor == 0 { break; }
+        }
+    }
+    Ok(())
+}
+
+/// Connection pool, connecting lazily so the server can start before Redis is reachable
+pub fn redis_pool(client: RedisClient, size: u32, timeout: Duration, breaker: CircuitBreaker) -> RedisPool {
+    let retry = client.retry;
+    let pool = r2d2::Pool::builder()
+        .max_size(size)
+        .connection_timeout(timeout)
+        .build_unchecked(client);
+    RedisPool { pool, retry, breaker: Arc::new(breaker) }
+}
+
+pub fn get_connection(client: &RedisPool) -> redis::RedisResult<r2d2::PooledConnection<RedisClient>> {
+    client.get().map_err(|e| redis::RedisError::from((redis::ErrorKind::IoError, "Redis connection unavailable", e.to_string())))
+}
+
+// refusals and failover replies, where the command never ran; a dropped connection may follow an applied write
+fn is_retryable(e: &redis::RedisError) -> bool {
+    e.is_connection_refusal() || matches!(e.kind(),
+        redis::ErrorKind::ReadOnly | redis::ErrorKind::MasterDown | redis::ErrorKind::BusyLoadingError | redis::ErrorKind::TryAgain | redis::ErrorKind::ClusterDown)
+}
+
+// errors meaning Redis could not serve the command, as opposed to replies such as WRONGTYPE
+fn is_unavailable(e: &redis::RedisError) -> bool {
+    is_retryable(e) || e.is_connection_dropped() || e.is_io_error() || e.is_timeout()
+}
 
-    let value = con.get(&key)?;
+const BREAKER_OPEN : &str = "Redis circuit breaker open";
+
+/// Error of calls rejected by an open circuit breaker, answered as `SERVICE_UNAVAILABLE`
+pub fn is_breaker_open(e: &redis::RedisError) -> bool {
+    e.kind() == redis::ErrorKind::ClientError && e.to_string().starts_with(BREAKER_OPEN)
+}
+
+/// Runs `f` on a pooled connection, retrying with exponential backoff while Redis fails over.
+/// Idempotent commands are also retried after timeouts, dropped connections and I/O errors, where a write may already have been applied.
+fn with_retry<T>(client: &RedisPool, command: &str, idempotent: bool, mut f: impl FnMut(&mut RedisConnection) -> redis::RedisResult<T>) -> redis::RedisResult<T> {
+    if !client.breaker.allow() {
+        return Err(redis::RedisError::from((redis::ErrorKind::ClientError, BREAKER_OPEN, format!("retry in {:?}", client.breaker.retry_after()))));
+    }
+    let mut attempt = 0;
+    loop {
+        // the pool already waits for a connection, so a pool timeout is not retried
+        let mut con = match observe_redis("CONNECT", || get_connection(client)) {
+            Ok(con) => con,
+            Err(e) => {
+                client.breaker.failure();
+                return Err(e);
+            }
+        };
+        match observe_redis(command, || f(&mut con)) {
+            Err(e) if (is_retryable(&e) || (idempotent && is_unavailable(&e))) && attempt < client.retry.attempts => {
+                con.failed();
+                let delay = client.retry.backoff.saturating_mul(2u32.saturating_pow(attempt)).min(client.retry.max_backoff);
+                log::warn!("Redis {} failed, retry {} in {:?}: {}", command, attempt + 1, delay, e);
+                drop(con);
+                std::thread::sleep(delay);
+                attempt += 1;
+            },
+            Err(e) if is_unavailable(&e) => {
+                con.failed();
+                client.breaker.failure();
+                return Err(e);
+            },
+            result => {
+                client.breaker.success();
+                return result;
+            }
+        }
+    }
+}
+
+pub fn get_kv(client: &RedisPool, key: &String) -> redis::RedisResult<Option<RedisRtn>> {
+    let value = with_retry(client, "GET", true, |con| con.get(key))?;
     match value {
         None => {
             log::warn!("Redis does not have key {}", &key);
@@ -42,26 +538,51 @@ pub fn get_kv(client: &redis::Client, key: &String) -> redis::RedisResult<Option
     }
 }
 
-pub fn set_kv(client: &redis::Client, key: &String, value: &String) -> redis::RedisResult<RedisRtn> {
-    let mut con = client.get_connection()?;
-
-    let result = con.set(&key, &value)?;
+pub fn set_kv(client: &RedisPool, key: &String, value: &String) -> redis::RedisResult<RedisRtn> {
+    let result = with_retry(client, "SET", false, |con| con.set(key, value))?;
     log::info!("Redis set ([key] {}; [value] {})", &key, &value);
     Ok(RedisRtn::Status(Status::String(result)))
 }
 
-pub fn del_kv(client: &redis::Client, key: &String) -> redis::RedisResult<RedisRtn> {
-    let mut con = client.get_connection()?;
+pub fn get_bytes(client: &RedisPool, key: &String) -> redis::RedisResult<Option<Vec<u8>>> {
+    let value : Option<Vec<u8>> = with_retry(client, "GET", true, |con| con.get(key))?;
+    match value {
+        None => {
+            log::warn!("Redis does not have key {}", &key);
+            Ok(None)
+        },
+        Some(x) => {
+            log::info!("Redis get ([key] {}; [bytes] {})", &key, x.len());
+            Ok(Some(x))
+        }
+    }
+}
+
+/// Bytes `start..=end` of a string, `None` when the key is missing or the range past its end
+pub fn get_range_bytes(client: &RedisPool, key: &String, start: usize, end: usize) -> redis::RedisResult<Option<Vec<u8>>> {
+    let value : Vec<u8> = with_retry(client, "GETRANGE", true, |con| con.getrange(key, start as isize, end as isize))?;
+    if value.is_empty() {
+        log::warn!("Redis does not have range {}-{} of key {}", start, end, &key);
+        return Ok(None);
+    }
+    log::info!("Redis getrange ([key] {}; [range] {}-{}; [bytes] {})", &key, start, end, value.len());
+    Ok(Some(value))
+}
+
+pub fn set_bytes(client: &RedisPool, key: &String, value: &[u8]) -> redis::RedisResult<RedisRtn> {
+    let result = with_retry(client, "SET", false, |con| con.set(key, value))?;
+    log::info!("Redis set ([key] {}; [bytes] {})", &key, value.len());
+    Ok(RedisRtn::Status(Status::String(result)))
+}
 
-    let result = con.del(&key)?;
+pub fn del_kv(client: &RedisPool, key: &String) -> redis::RedisResult<RedisRtn> {
+    let result = with_retry(client, "DEL", false, |con| con.del(key))?;
     log::info!("Redis delete ([key] {})", &key);
     Ok(RedisRtn::Status(Status::Int(result)))
 }
 
-pub fn get_hash_kfv(client: &redis::Client, id: &String, key: &String) -> redis::RedisResult<Option<RedisRtn>> {
-    let mut con = client.get_connection()?;
-
-    let value = con.hget(&id, &key)?;
+pub fn get_hash_kfv(client: &RedisPool, id: &String, key: &String) -> redis::RedisResult<Option<RedisRtn>> {
+    let value = with_retry(client, "HGET", true, |con| con.hget(id, key))?;
     match value {
         None => {
             log::warn!("Redis hash does not have id {} key {}", &id, &key);
@@ -74,34 +595,171 @@ pub fn get_hash_kfv(client: &redis::Client, id: &String, key: &String) -> redis:
     }
 }
 
-pub fn set_hash_kfv(client: &redis::Client, id: &String, key: &String, value: &String) -> redis::RedisResult<RedisRtn> {
-    let mut con = client.get_connection()?;
-
-    let result = con.hset(&id, &key, &value)?;
+pub fn set_hash_kfv(client: &RedisPool, id: &String, key: &String, value: &String) -> redis::RedisResult<RedisRtn> {
+    let result = with_retry(client, "HSET", false, |con| con.hset(id, key, value))?;
     log::info!("Redis hash set ([id] {}; [key] {}; [value] {})", &id, &key, &value);
     Ok(RedisRtn::Status(Status::Int(result)))
 }
 
-pub fn set_sorted_kvs(client: &redis::Client, key: &String, value: &String, score: isize) -> redis::RedisResult<RedisRtn> {
-    let mut con = client.get_connection()?;
+/// Sets the field only when absent, returning whether it was set
+pub fn set_hash_nx(client: &RedisPool, id: &String, key: &String, value: &String) -> redis::RedisResult<bool> {
+    let result : bool = with_retry(client, "HSETNX", false, |con| con.hset_nx(id, key, value))?;
+    log::info!("Redis hash set if absent ([id] {}; [key] {}; [value] {}; [set] {})", &id, &key, &value, result);
+    Ok(result)
+}
+
+pub fn get_hash_bytes(client: &RedisPool, id: &String, key: &String) -> redis::RedisResult<Option<Vec<u8>>> {
+    let value : Option<Vec<u8>> = with_retry(client, "HGET", true, |con| con.hget(id, key))?;
+    match value {
+        None => {
+            log::warn!("Redis hash does not have id {} key {}", &id, &key);
+            Ok(None)
+        },
+        Some(x) => {
+            log::info!("Redis hash get ([id] {}; [key] {}; [bytes] {})", &id, &key, x.len());
+            Ok(Some(x))
+        }
+    }
+}
+
+pub fn set_hash_bytes(client: &RedisPool, id: &String, key: &String, value: &[u8]) -> redis::RedisResult<RedisRtn> {
+    let result = with_retry(client, "HSET", false, |con| con.hset(id, key, value))?;
+    log::info!("Redis hash set ([id] {}; [key] {}; [bytes] {})", &id, &key, value.len());
+    Ok(RedisRtn::Status(Status::Int(result)))
+}
+
+pub fn del_hash_kfv(client: &RedisPool, id: &String, key: &String) -> redis::RedisResult<RedisRtn> {
+    let result = with_retry(client, "HDEL", false, |con| con.hdel(id, key))?;
+    log::info!("Redis hash delete ([id] {}; [key] {})", &id, &key);
+    Ok(RedisRtn::Status(Status::Int(result)))
+}
+
+pub fn get_hash_all(client: &RedisPool, id: &String) -> redis::RedisResult<Vec<(String, String)>> {
+    let value : Vec<(String, String)> = with_retry(client, "HGETALL", true, |con| con.hgetall(id))?;
+    log::info!("Redis hash get all ([id] {}; [fields] {})", &id, value.len());
+    Ok(value)
+}
+
+pub fn incr_hash_kfv(client: &RedisPool, id: &String, key: &String, delta: isize) -> redis::RedisResult<RedisRtn> {
+    let result = with_retry(client, "HINCRBY", false, |con| con.hincr(id, key, delta))?;
+    log::info!("Redis hash incr ([id] {}; [key] {}; [delta] {}; [value] {})", &id, &key, delta, result);
+    Ok(RedisRtn::Status(Status::Int(result)))
+}
+
+// HINCRBY then HSET, only while the hash exists
+const INCR_HASH_XX : &str = "if redis.call('EXISTS', KEYS[1]) == 0 then return false end \
+    local n = redis.call('HINCRBY', KEYS[1], ARGV[1], ARGV[2]) \
+    redis.call('HSET', KEYS[1], ARGV[3], ARGV[4]) \
+    return n";
+
+/// Increments `key` and sets `stamp` to `value` if the hash still exists, so a late write does not recreate a deleted hash.
+/// `None` when the hash is missing.
+pub fn incr_hash_xx(client: &RedisPool, id: &String, key: &String, delta: isize, stamp: &String, value: &String) -> redis::RedisResult<Option<isize>> {
+    let result : Option<isize> = with_retry(client, "EVAL", false, |con| {
+        redis::cmd("EVAL").arg(INCR_HASH_XX).arg(1).arg(id).arg(key).arg(delta).arg(stamp).arg(value).query(con)
+    })?;
+    log::info!("Redis hash incr if exists ([id] {}; [key] {}; [delta] {}; [value] {:?})", &id, &key, delta, result);
+    Ok(result)
+}
+
+pub fn set_set_kvs(client: &RedisPool, key: &String, member: &String) -> redis::RedisResult<RedisRtn> {
+    let result = with_retry(client, "SADD", false, |con| con.sadd(key, member))?;
+    log::info!("Redis set add ([key] {}; [member] {})", &key, &member);
+    Ok(RedisRtn::Status(Status::Int(result)))
+}
+
+pub fn set_set_many(client: &RedisPool, key: &String, members: &[String]) -> redis::RedisResult<RedisRtn> {
+    let result = with_retry(client, "SADD", false, |con| con.sadd(key, members))?;
+    log::info!("Redis set add ([key] {}; [members] {})", &key, members.len());
+    Ok(RedisRtn::Status(Status::Int(result)))
+}
+
+// keys split so that each pipeline stays within one slot, a cluster routing a pipeline by its first key
+fn slot_batches<'a>(con: &RedisConnection, keys: &'a [String]) -> Vec<Vec<&'a String>> {
+    if !matches!(con.link, Link::Cluster { .. }) { return vec![keys.iter().collect()] }
+    let mut slots : BTreeMap<u16, Vec<&String>> = BTreeMap::new();
+    for key in keys.iter() {
+        slots.entry(redis::cluster_routing::get_slot(key.as_bytes())).or_default().push(key);
+    }
+    slots.into_values().collect()
+}
+
+/// Adds `member` to each set of `keys`, pipelined per cluster slot
+pub fn set_sets_kvs(client: &RedisPool, keys: &[String], member: &String) -> redis::RedisResult<()> {
+    with_retry(client, "SADD", true, |con| {
+        for batch in slot_batches(con, keys) {
+            let mut pipe = redis::pipe();
+            for key in batch { pipe.sadd(key, member).ignore(); }
+            pipe.query::<()>(con)?;
+        }
+        Ok(())
+    })?;
+    log::info!("Redis sets add ([keys] {}; [member] {})", keys.len(), &member);
+    Ok(())
+}
+
+/// Removes `member` from each set of `keys`, pipelined per cluster slot
+pub fn del_sets_kvs(client: &RedisPool, keys: &[String], member: &String) -> redis::RedisResult<()> {
+    with_retry(client, "SREM", true, |con| {
+        for batch in slot_batches(con, keys) {
+            let mut pipe = redis::pipe();
+            for key in batch { pipe.srem(key, member).ignore(); }
+            pipe.query::<()>(con)?;
+        }
+        Ok(())
+    })?;
+    log::info!("Redis sets delete ([keys] {}; [member] {})", keys.len(), &member);
+    Ok(())
+}
+
+pub fn del_set_kvs(client: &RedisPool, key: &String, member: &String) -> redis::RedisResult<RedisRtn> {
+    let result = with_retry(client, "SREM", false, |con| con.srem(key, member))?;
+    log::info!("Redis set delete ([key] {}; [member] {})", &key, &member);
+    Ok(RedisRtn::Status(Status::Int(result)))
+}
+
+pub fn get_set_kv(client: &RedisPool, key: &String) -> redis::RedisResult<Option<RedisRtn>> {
+    let value : Option<Vec<String>> = with_retry(client, "SMEMBERS", true, |con| con.smembers(key))?;
+    match value {
+        None => {
+            log::warn!("Redis set does not have key {}", &key);
+            Ok(None)
+        },
+        Some(x) => {
+            log::info!("Redis set get ([key] {}; [members] {})", &key, x.join(","));
+            Ok(Some(RedisRtn::Payloads(x)))
+        }
+    }
+}
 
-    let result = con.zadd(&key, &value, score)?;
+pub fn set_sorted_kvs(client: &RedisPool, key: &String, value: &String, score: isize) -> redis::RedisResult<RedisRtn> {
+    let result = with_retry(client, "ZADD", false, |con| con.zadd(key, value, score))?;
     log::info!("Redis zset set ([key] {}; [value] {}; [score] {})", &key, &value, score);
     Ok(RedisRtn::Status(Status::Int(result)))
 }
 
-pub fn del_sorted_kvs(client: &redis::Client, key: &String, member: &String) -> redis::RedisResult<RedisRtn> {
-    let mut con = client.get_connection()?;
+/// Adds `value` unless already a member, keeping its score
+pub fn add_sorted_kvs(client: &RedisPool, key: &String, value: &String, score: i64) -> redis::RedisResult<RedisRtn> {
+    let result = with_retry(client, "ZADD", false, |con| redis::cmd("ZADD").arg(key).arg("NX").arg(score).arg(value).query(con))?;
+    log::info!("Redis zset add ([key] {}; [value] {}; [score] {})", &key, &value, score);
+    Ok(RedisRtn::Status(Status::Int(result)))
+}
+
+/// Increments the score of `value` if still a member, so a concurrently dropped member is not brought back
+pub fn incr_sorted_kvs(client: &RedisPool, key: &String, value: &String, delta: isize) -> redis::RedisResult<Option<f64>> {
+    let result : Option<f64> = with_retry(client, "ZADD", false, |con| redis::cmd("ZADD").arg(key).arg("XX").arg("INCR").arg(delta).arg(value).query(con))?;
+    log::info!("Redis zset incr ([key] {}; [value] {}; [delta] {}; [score] {:?})", &key, &value, delta, result);
+    Ok(result)
+}
 
-    let result = con.zrem(&key, &member)?;
+pub fn del_sorted_kvs(client: &RedisPool, key: &String, member: &String) -> redis::RedisResult<RedisRtn> {
+    let result = with_retry(client, "ZREM", false, |con| con.zrem(key, member))?;
     log::info!("Redis zset delete ([key] {}; [member] {})", &key, &member);
     Ok(RedisRtn::Status(Status::Int(result)))
 }
 
-pub fn get_sorted_kv(client: &redis::Client, key: &String) -> redis::RedisResult<Option<RedisRtn>> {
-    let mut con = client.get_connection()?;
-
-    let value : Option<Vec<String>> = con.zrange(&key, 0, -1)?;
+pub fn get_sorted_kv(client: &RedisPool, key: &String) -> redis::RedisResult<Option<RedisRtn>> {
+    let value : Option<Vec<String>> = with_retry(client, "ZRANGE", true, |con| con.zrange(key, 0, -1))?;
     match value {
         None => {
             log::warn!("Redis zset does not have key {}", &key);
@@ -112,4 +770,141 @@ pub fn get_sorted_kv(client: &redis::Client, key: &String) -> redis::RedisResult
             Ok(Some(RedisRtn::Payloads(x)))
         }
     }
-}
\ No newline at end of file
+}
+pub fn get_sorted_scores(client: &RedisPool, key: &String) -> redis::RedisResult<Vec<(String, f64)>> {
+    let value : Vec<(String, f64)> = with_retry(client, "ZRANGE", true, |con| con.zrange_withscores(key, 0, -1))?;
+    log::info!("Redis zset get ([key] {}; [members] {})", &key, value.len());
+    Ok(value)
+}
+
+pub fn get_schema_version(client: &RedisPool, ks: &KeySpace) -> redis::RedisResult<Option<isize>> {
+    match get_kv(client, &str_k_schema(ks))? {
+        Some(RedisRtn::Payload(x)) => Ok(Some(x.parse::<isize>().unwrap_or(0))),
+        _ => Ok(None)
+    }
+}
+
+pub fn set_schema_version(client: &RedisPool, ks: &KeySpace, version: isize) -> redis::RedisResult<RedisRtn> {
+    set_kv(client, &str_k_schema(ks), &version.to_string())
+}
+
+fn has_keys(client: &RedisPool, pattern: &str) -> redis::RedisResult<bool> {
+    let mut con = observe_redis("CONNECT", || get_connection(client))?;
+
+    for node in scan_nodes(&mut con)?.iter() {
+        let mut cursor : u64 = 0;
+        loop {
+            let (next, keys) = scan_page(&mut con, node, cursor, pattern, 1000)?;
+            if !keys.is_empty() { return Ok(true) }
+            cursor = next;
+            if cursor == 0 { break; }
+        }
+    }
+    Ok(false)
+}
+
+// keys of buckets and slangs in any namespace, which only this app writes, so that another app
+// sharing Redis under the same prefix is not taken for data predating versioning
+fn legacy_key_patterns(ks: &KeySpace) -> [String; 2] {
+    [format!("{}*bkt:hash:id:*", ks.prefix), format!("{}*idx:str:slg:*", ks.prefix)]
+}
+
+/// Stamps the schema version on first start and rejects a stored version the binary cannot serve
+pub fn check_schema_version(client: &RedisPool, ks: &KeySpace) -> Result<isize, String> {
+    let version = match get_schema_version(client, ks) {
+        Err(e) => return Err(format!("cannot read schema version: {}", e)),
+        Ok(Some(x)) => x,
+        Ok(None) => {
+            // unstamped buckets or slangs predate versioning, a key space without any starts at the latest layout
+            let mut version = SCHEMA_VERSION;
+            for pattern in legacy_key_patterns(ks).iter() {
+                match has_keys(client, pattern) {
+                    Err(e) => return Err(format!("cannot scan keys: {}", e)),
+                    Ok(true) => { version = SCHEMA_VERSION_LEGACY; break; },
+                    Ok(false) => ()
+                }
+            }
+            if let Err(e) = set_schema_version(client, ks, version) {
+                return Err(format!("cannot write schema version: {}", e));
+            }
+            version
+        }
+    };
+    if !(SCHEMA_VERSION_MIN..=SCHEMA_VERSION).contains(&version) {
+        return Err(format!("schema version {} found under {}, binary serves {} to {}, run bucket-jai-migrate",
+            version, str_k_schema(ks), SCHEMA_VERSION_MIN, SCHEMA_VERSION));
+    }
+    if version < SCHEMA_VERSION {
+        log::warn!("schema version {} is behind {}, run bucket-jai-migrate", version, SCHEMA_VERSION);
+    }
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn io_error(kind: std::io::ErrorKind) -> redis::RedisError {
+        redis::RedisError::from(std::io::Error::from(kind))
+    }
+
+    #[test]
+    fn retries_failover_replies_and_refusals() {
+        for kind in [redis::ErrorKind::ReadOnly, redis::ErrorKind::MasterDown, redis::ErrorKind::BusyLoadingError, redis::ErrorKind::TryAgain, redis::ErrorKind::ClusterDown] {
+            assert!(is_retryable(&redis::RedisError::from((kind, "failover"))));
+        }
+        assert!(is_retryable(&io_error(std::io::ErrorKind::ConnectionRefused)));
+    }
+
+    #[test]
+    fn dropped_connection_only_retried_when_idempotent() {
+        let e = io_error(std::io::ErrorKind::ConnectionReset);
+        assert!(e.is_connection_dropped());
+        assert!(!is_retryable(&e));
+        assert!(is_unavailable(&e));
+    }
+
+    #[test]
+    fn replies_are_not_unavailability() {
+        assert!(!is_unavailable(&redis::RedisError::from((redis::ErrorKind::TypeError, "WRONGTYPE"))));
+    }
+
+    #[test]
+    fn legacy_data_detected_by_app_keys_only() {
+        let ks = KeySpace { prefix: "app:".to_string(), namespace: "team".to_string() };
+        let patterns = legacy_key_patterns(&ks);
+        assert_eq!(patterns, ["app:*bkt:hash:id:*".to_string(), "app:*idx:str:slg:*".to_string()]);
+        // bucket and slang keys of any namespace match
+        let root = KeySpace { prefix: "app:".to_string(), namespace: DEFAULT_NAMESPACE.to_string() };
+        assert!(str_k_slang(&ks, &"x".to_string()).starts_with("app:ns:team:idx:str:slg:"));
+        assert!(hash_k_id(&root, &"x".to_string()).starts_with("app:bkt:hash:id:"));
+    }
+
+    #[test]
+    fn namespace_of_key_inverts_key_builders() {
+        let id = "abc".to_string();
+        for (prefix, namespace) in [("", DEFAULT_NAMESPACE), ("app:", DEFAULT_NAMESPACE), ("", "team"), ("app:", "team-b")] {
+            let ks = KeySpace { prefix: prefix.to_string(), namespace: namespace.to_string() };
+            assert_eq!(namespace_of_key(&ks, &hash_k_id(&ks, &id)), namespace);
+            assert_eq!(namespace_of_key(&ks, &str_k_slang(&ks, &id)), namespace);
+        }
+        // untagged keys of schema versions before 3
+        let ks = KeySpace { prefix: "app:".to_string(), namespace: DEFAULT_NAMESPACE.to_string() };
+        assert_eq!(namespace_of_key(&ks, "app:ns:team:bkt:hash:id:abc"), "team");
+        assert_eq!(namespace_of_key(&ks, "app:bkt:hash:id:abc"), DEFAULT_NAMESPACE);
+    }
+
+    #[test]
+    fn keys_of_a_bucket_share_a_cluster_slot() {
+        let ks = KeySpace { prefix: "app:".to_string(), namespace: "team".to_string() };
+        let id = "0123abcd".to_string();
+        let slot = |key: String| redis::cluster_routing::get_slot(key.as_bytes());
+        let expected = slot(hash_k_id(&ks, &id));
+        for key in [zset_k_id(&ks, &id), zset_k_created(&ks, &id), hash_k_labels(&ks, &id), set_k_terms(&ks, &id)] {
+            assert_eq!(slot(key), expected);
+        }
+        // chunks share the slot of their tag
+        let tag = "upload-1".to_string();
+        assert_eq!(slot(str_k_chunk(&ks, &tag, 0)), slot(str_k_chunk(&ks, &tag, 7)));
+    }
+}
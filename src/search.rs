@@ -0,0 +1,84 @@
+use std::collections::BTreeSet;
+
+use crate::sniff::essence;
+
+// longer runs are ids, hashes or base64 nobody searches for
+const MAX_TERM_CHARS : usize = 64;
+const SNIPPET_CHARS : usize = 160;
+
+/// Whether buckets of this mime have their content indexed
+pub fn is_indexable(mime: &str) -> bool {
+    let mime = essence(mime);
+    mime.starts_with("text/") || mime == "application/json" || mime.ends_with("+json")
+}
+
+// byte bounds of the words of `text`, words being runs of alphanumeric characters
+fn words(text: &str) -> impl Iterator<Item = (usize, usize)> + '_ {
+    let mut start = None;
+    text.char_indices().chain(std::iter::once((text.len(), ' '))).filter_map(move |(i, c)| {
+        match (start, c.is_alphanumeric()) {
+            (None, true) => { start = Some(i); None },
+            (Some(s), false) => { start = None; Some((s, i)) },
+            _ => None
+        }
+    })
+}
+
+/// Distinct lowercase terms of `text`
+pub fn terms(text: &str) -> BTreeSet<String> {
+    words(text)
+        .map(|(s, e)| text[s..e].to_lowercase())
+        .filter(|x| x.chars().count() <= MAX_TERM_CHARS)
+        .collect()
+}
+
+/// Text of `text` around the first word among `terms`, or its beginning when none is found,
+/// with whitespace collapsed and an ellipsis where it is cut
+pub fn snippet(text: &str, terms: &BTreeSet<String>) -> String {
+    let hit = words(text).find(|(s, e)| terms.contains(&text[*s..*e].to_lowercase())).map_or(0, |(s, _)| s);
+    // start a third of the window before the hit, on a char boundary
+    let before = text[..hit].char_indices().rev().nth(SNIPPET_CHARS / 3 - 1).map_or(0, |(i, _)| i);
+    let end = text[before..].char_indices().nth(SNIPPET_CHARS).map_or(text.len(), |(i, _)| before + i);
+
+    let mut _snippet = String::with_capacity(end - before + 8);
+    if before > 0 { _snippet.push('…'); }
+    _snippet.push_str(&text[before..end].split_whitespace().collect::<Vec<_>>().join(" "));
+    if end < text.len() { _snippet.push('…'); }
+    _snippet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn terms_are_distinct_lowercase_words() {
+        let found = terms("Héllo, wörld! hello_WORLD 42 {\"key\": 1}");
+        assert_eq!(found.into_iter().collect::<Vec<_>>(), ["1", "42", "hello", "héllo", "key", "world", "wörld"]);
+        assert!(terms(&"x".repeat(MAX_TERM_CHARS + 1)).is_empty());
+        assert_eq!(terms(&"é".repeat(MAX_TERM_CHARS)).len(), 1);
+    }
+
+    #[test]
+    fn indexable_mimes_are_text_and_json() {
+        assert!(is_indexable("text/plain; charset=utf-8"));
+        assert!(is_indexable("application/ld+json"));
+        assert!(!is_indexable("image/png"));
+    }
+
+    #[test]
+    fn snippet_around_first_hit() {
+        let matched = terms("needle");
+        assert_eq!(snippet("a  short\ntext", &matched), "a short text");
+
+        let text = format!("{} needle {}", "word ".repeat(40), "tail ".repeat(40));
+        let cut = snippet(&text, &matched);
+        assert!(cut.starts_with('…') && cut.ends_with('…'));
+        assert!(cut.contains("needle"));
+        assert!(cut.chars().count() <= SNIPPET_CHARS + 2);
+
+        // no hit starts at the beginning, on char boundaries
+        let text = "é".repeat(SNIPPET_CHARS * 2);
+        assert_eq!(snippet(&text, &matched), format!("{}…", "é".repeat(SNIPPET_CHARS)));
+    }
+}
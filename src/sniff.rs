@@ -0,0 +1,188 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// Leading bytes of a payload looked at to sniff its mime
+pub const SNIFF_BYTES : usize = 512;
+
+/// What to do when the declared mime contradicts the sniffed one
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MimeCheck {
+    Off,    // trust the declared mime
+    Flag,   // keep the declared mime, record the sniffed one and serve as attachment
+    Reject  // fail with MIME_MISMATCH
+}
+
+impl FromStr for MimeCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(MimeCheck::Off),
+            "flag" => Ok(MimeCheck::Flag),
+            "reject" => Ok(MimeCheck::Reject),
+            _ => Err(format!("unknown mime check {}, expected off, flag or reject", s))
+        }
+    }
+}
+
+const MAGIC : &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"\x00\x00\x01\x00", "image/x-icon"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (b"BZh", "application/x-bzip2"),
+    (b"\xfd7zXZ\x00", "application/x-xz"),
+    (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (b"\x00asm", "application/wasm"),
+    (b"OggS", "application/ogg"),
+    (b"fLaC", "audio/flac"),
+    (b"ID3", "audio/mpeg"),
+    (b"\x1a\x45\xdf\xa3", "video/webm"),
+    (b"wOFF", "font/woff"),
+    (b"wOF2", "font/woff2")
+];
+
+// markup a browser renders as HTML, matched case-insensitively after leading whitespace
+const HTML_TAGS : &[&str] = &["<!doctype html", "<html", "<head", "<body", "<script", "<iframe", "<style", "<title", "<a ", "<div", "<img", "<table", "<p>", "<br", "<h1", "<font", "<b>", "<!--"];
+
+/// Mime the leading bytes of a payload identify, `None` when nothing does
+pub fn sniff(head: &[u8]) -> Option<&'static str> {
+    let head = &head[..head.len().min(SNIFF_BYTES)];
+    if let Some((_, x)) = MAGIC.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return Some(x);
+    }
+    // RIFF and ISO media containers name their format after a size
+    if head.len() >= 12 && head.starts_with(b"RIFF") {
+        match &head[8..12] {
+            b"WEBP" => return Some("image/webp"),
+            b"WAVE" => return Some("audio/wav"),
+            b"AVI " => return Some("video/x-msvideo"),
+            _ => {}
+        }
+    }
+    if head.len() >= 12 && &head[4..8] == b"ftyp" {
+        return Some(match &head[8..12] {
+            b"avif" => "image/avif",
+            b"heic" | b"heix" => "image/heic",
+            b"qt  " => "video/quicktime",
+            _ => "video/mp4"
+        });
+    }
+
+    let text = head.strip_prefix(b"\xef\xbb\xbf").unwrap_or(head);
+    let start = text.iter().position(|x| !x.is_ascii_whitespace()).unwrap_or(text.len());
+    let lower = text[start..].to_ascii_lowercase();
+    if HTML_TAGS.iter().any(|x| lower.starts_with(x.as_bytes())) { return Some("text/html") }
+    if lower.starts_with(b"<svg") || (lower.starts_with(b"<?xml") && contains(&lower, b"<svg")) { return Some("image/svg+xml") }
+    if lower.starts_with(b"<?xml") { return Some("application/xml") }
+    None
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|x| x == needle)
+}
+
+/// Mime of a payload sent without one, plain text when nothing is sniffed from UTF-8 content
+pub fn default_mime(head: &[u8], is_utf8: bool) -> &'static str {
+    match sniff(head) {
+        Some(x) => x,
+        None if is_utf8 => "text/plain",
+        None => "application/octet-stream"
+    }
+}
+
+/// Type and subtype of a mime, lowercase and without parameters
+pub fn essence(mime: &str) -> String {
+    mime.split(';').next().unwrap_or("").trim().to_ascii_lowercase()
+}
+
+/// Whether content sniffed as `sniffed` may be labelled `declared`
+pub fn mime_matches(declared: &str, sniffed: &str) -> bool {
+    let declared = essence(declared);
+    declared == sniffed
+        // office documents, jars and epubs are zip containers
+        || (sniffed == "application/zip" && declared.starts_with("application/"))
+        || (sniffed == "application/xml" && (declared == "text/xml" || declared.ends_with("+xml")))
+        || (sniffed == "image/svg+xml" && declared == "text/xml")
+        || (sniffed == "text/html" && declared == "application/xhtml+xml")
+        || (sniffed == "image/x-icon" && declared == "image/vnd.microsoft.icon")
+        || (sniffed == "audio/mpeg" && declared == "audio/mp3")
+        || (sniffed == "application/ogg" && (declared.starts_with("audio/") || declared.starts_with("video/")))
+        || (sniffed == "video/mp4" && (declared.starts_with("video/") || declared.starts_with("audio/mp4")))
+        || (sniffed == "video/webm" && (declared == "audio/webm" || declared == "video/x-matroska"))
+}
+
+/// Whether a browser would run scripts in content of this mime when opening it, so it is served as attachment
+pub fn is_active(mime: &str) -> bool {
+    let mime = essence(mime);
+    matches!(mime.as_str(),
+        "text/html" | "application/xhtml+xml" | "image/svg+xml" | "text/xml" | "application/xml" | "text/xsl"
+        | "text/javascript" | "application/javascript" | "application/ecmascript" | "text/ecmascript"
+        | "application/x-shockwave-flash" | "application/pdf" | "multipart/x-mixed-replace")
+        || mime.ends_with("+xml")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_magic_numbers_and_containers() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n...."), Some("image/png"));
+        assert_eq!(sniff(b"%PDF-1.7"), Some("application/pdf"));
+        assert_eq!(sniff(b"RIFF\x00\x00\x00\x00WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(b"RIFF\x00\x00\x00\x00JUNK"), None);
+        assert_eq!(sniff(b"\x00\x00\x00\x18ftypheic"), Some("image/heic"));
+        assert_eq!(sniff(b"\x00\x00\x00\x18ftypisom"), Some("video/mp4"));
+    }
+
+    #[test]
+    fn sniffs_markup_after_bom_and_whitespace() {
+        assert_eq!(sniff(b"\xef\xbb\xbf  \n<!DOCTYPE HTML><p>"), Some("text/html"));
+        assert_eq!(sniff(b"<?xml version=\"1.0\"?><svg xmlns=\"\">"), Some("image/svg+xml"));
+        assert_eq!(sniff(b"<?xml version=\"1.0\"?><feed/>"), Some("application/xml"));
+        assert_eq!(sniff(b"hello <html>"), None);
+        // markup past the sniffed bytes is not looked at
+        let late = [vec![b' '; SNIFF_BYTES], b"<html>".to_vec()].concat();
+        assert_eq!(sniff(&late), None);
+    }
+
+    #[test]
+    fn default_mime_by_content() {
+        assert_eq!(default_mime(b"plain words", true), "text/plain");
+        assert_eq!(default_mime(b"\x00\x01\x02", false), "application/octet-stream");
+        assert_eq!(default_mime(b"GIF89a", false), "image/gif");
+    }
+
+    #[test]
+    fn declared_mimes_matching_sniffed_ones() {
+        assert!(mime_matches("Image/PNG; charset=binary", "image/png"));
+        assert!(mime_matches("application/vnd.openxmlformats-officedocument.wordprocessingml.document", "application/zip"));
+        assert!(mime_matches("application/atom+xml", "application/xml"));
+        assert!(mime_matches("audio/mp3", "audio/mpeg"));
+        assert!(!mime_matches("image/png", "text/html"));
+        assert!(!mime_matches("text/plain", "application/zip"));
+        assert!(!mime_matches("image/jpeg", "image/png"));
+    }
+
+    #[test]
+    fn active_content_served_as_attachment() {
+        assert!(is_active("text/html; charset=utf-8"));
+        assert!(is_active("application/rss+xml"));
+        assert!(!is_active("text/plain"));
+        assert!(!is_active("image/png"));
+    }
+
+    #[test]
+    fn mime_check_parsed_from_flags() {
+        assert_eq!("reject".parse::<MimeCheck>(), Ok(MimeCheck::Reject));
+        assert!("strict".parse::<MimeCheck>().is_err());
+    }
+}
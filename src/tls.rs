@@ -0,0 +1,233 @@
+use std::{
+    fmt,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::SystemTime,
+};
+
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig};
+
+use crate::config::TlsConfig;
+
+/// Marks a connection whose client presented a certificate verified against the client CA
+#[derive(Clone)]
+pub struct ClientCert;
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path).map(BufReader::new).map_err(|e| format!("cannot read {}: {}", path.display(), e))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, String> {
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid certificate {}: {}", cert_path.display(), e))?;
+    if certs.is_empty() { return Err(format!("no certificate in {}", cert_path.display())) }
+
+    let key = rustls_pemfile::private_key(&mut open(key_path)?)
+        .map_err(|e| format!("invalid key {}: {}", key_path.display(), e))?
+        .ok_or_else(|| format!("no private key in {}", key_path.display()))?;
+    let signing_key = rustls::crypto::ring::sign::any_supported_type(&key)
+        .map_err(|e| format!("unsupported key {}: {}", key_path.display(), e))?;
+
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+/// Serves the certificate last loaded from disk, swapped in place by `reload`
+pub struct ReloadingResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    key: RwLock<Arc<CertifiedKey>>,
+    loaded_at: Mutex<(Option<SystemTime>, Option<SystemTime>)>
+}
+
+impl fmt::Debug for ReloadingResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReloadingResolver").field("cert_path", &self.cert_path).field("key_path", &self.key_path).finish()
+    }
+}
+
+impl ResolvesServerCert for ReloadingResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.key.read().ok().map(|x| x.clone())
+    }
+}
+
+impl ReloadingResolver {
+    pub fn new(cert_path: &Path, key_path: &Path) -> Result<ReloadingResolver, String> {
+        let key = load_certified_key(cert_path, key_path)?;
+        Ok(ReloadingResolver {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            key: RwLock::new(Arc::new(key)),
+            loaded_at: Mutex::new((modified(cert_path), modified(key_path)))
+        })
+    }
+
+    /// Reloads the certificate when forced or when either file changed, keeping the current one on failure
+    pub fn reload(&self, force: bool) -> Result<bool, String> {
+        let stamps = (modified(&self.cert_path), modified(&self.key_path));
+        let mut loaded_at = self.loaded_at.lock().map_err(|e| e.to_string())?;
+        if !force && *loaded_at == stamps { return Ok(false) }
+
+        let key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.key.write().map_err(|e| e.to_string())? = Arc::new(key);
+        *loaded_at = stamps;
+        log::info!("TLS certificate reloaded from {}", self.cert_path.display());
+        Ok(true)
+    }
+}
+
+/// rustls configuration serving certificates through `resolver`, asking for optional client certificates when a client CA is set
+pub fn server_config(tls: &TlsConfig, resolver: Arc<ReloadingResolver>) -> Result<ServerConfig, String> {
+    let builder = ServerConfig::builder();
+    let builder = match &tls.client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in rustls_pemfile::certs(&mut open(path)?) {
+                let cert = cert.map_err(|e| format!("invalid client CA {}: {}", path.display(), e))?;
+                roots.add(cert).map_err(|e| format!("invalid client CA {}: {}", path.display(), e))?;
+            }
+            // the handshake accepts anonymous clients, admin operations check for a certificate per request
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .allow_unauthenticated()
+                .build()
+                .map_err(|e| format!("invalid client CA {}: {}", path.display(), e))?;
+            builder.with_client_cert_verifier(verifier)
+        },
+        None => builder.with_no_client_auth()
+    };
+    let mut config = builder.with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, ServerConnection};
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tls").join(name)
+    }
+
+    // certificate and key files of fixture `name` copied to a directory of their own
+    fn copy_pair(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::copy(fixture(&format!("{}.pem", name)), &cert).unwrap();
+        std::fs::copy(fixture(&format!("{}.key", name)), &key).unwrap();
+        (cert, key)
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bucket-jai-tls-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn served(resolver: &ReloadingResolver) -> Vec<u8> {
+        resolver.key.read().unwrap().cert[0].to_vec()
+    }
+
+    fn pem_cert(name: &str) -> Vec<u8> {
+        rustls_pemfile::certs(&mut open(&fixture(name)).unwrap()).next().unwrap().unwrap().to_vec()
+    }
+
+    // file modification times are coarse on some filesystems, so changes are stamped explicitly
+    fn stamp(path: &Path, secs: u64) {
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        File::options().write(true).open(path).unwrap().set_modified(time).unwrap();
+    }
+
+    #[test]
+    fn reloaded_only_when_files_change() {
+        let dir = temp_dir("change");
+        let (cert, key) = copy_pair(&dir, "server-a");
+        let resolver = ReloadingResolver::new(&cert, &key).unwrap();
+        assert_eq!(resolver.reload(false), Ok(false));
+        assert_eq!(served(&resolver), pem_cert("server-a.pem"));
+
+        copy_pair(&dir, "server-b");
+        stamp(&cert, 1_000_000);
+        assert_eq!(resolver.reload(false), Ok(true));
+        assert_eq!(served(&resolver), pem_cert("server-b.pem"));
+        assert_eq!(resolver.reload(false), Ok(false));
+        assert_eq!(resolver.reload(true), Ok(true));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bad_certificate_keeps_the_served_one() {
+        let dir = temp_dir("bad");
+        let (cert, key) = copy_pair(&dir, "server-a");
+        let resolver = ReloadingResolver::new(&cert, &key).unwrap();
+
+        std::fs::write(&cert, "not a certificate").unwrap();
+        stamp(&cert, 1_000_000);
+        assert!(resolver.reload(false).unwrap_err().starts_with("no certificate in"));
+        assert_eq!(served(&resolver), pem_cert("server-a.pem"));
+        // a failed reload is retried until the files are fixed
+        copy_pair(&dir, "server-b");
+        stamp(&cert, 2_000_000);
+        assert_eq!(resolver.reload(false), Ok(true));
+        assert_eq!(served(&resolver), pem_cert("server-b.pem"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn tls_config(client_ca: Option<&str>) -> Result<ServerConfig, String> {
+        let tls = TlsConfig { client_ca_path: client_ca.map(fixture), ..TlsConfig::default() };
+        let resolver = ReloadingResolver::new(&fixture("server-a.pem"), &fixture("server-a.key")).unwrap();
+        server_config(&tls, Arc::new(resolver))
+    }
+
+    // completes a handshake in memory, returning whether the server saw a client certificate
+    fn handshake(server: ServerConfig, client_cert: bool) -> bool {
+        let mut roots = RootCertStore::empty();
+        roots.add(pem_cert("ca.pem").into()).unwrap();
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        let config = match client_cert {
+            true => {
+                let certs = vec![pem_cert("client.pem").into()];
+                let key = rustls_pemfile::private_key(&mut open(&fixture("client.key")).unwrap()).unwrap().unwrap();
+                builder.with_client_auth_cert(certs, key).unwrap()
+            },
+            false => builder.with_no_client_auth()
+        };
+        let mut client = ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap();
+        let mut server = ServerConnection::new(Arc::new(server)).unwrap();
+        while client.is_handshaking() || server.is_handshaking() {
+            let mut buf = Vec::new();
+            client.write_tls(&mut buf).unwrap();
+            server.read_tls(&mut buf.as_slice()).unwrap();
+            server.process_new_packets().unwrap();
+            let mut buf = Vec::new();
+            server.write_tls(&mut buf).unwrap();
+            client.read_tls(&mut buf.as_slice()).unwrap();
+            client.process_new_packets().unwrap();
+        }
+        server.peer_certificates().is_some_and(|x| !x.is_empty())
+    }
+
+    #[test]
+    fn client_certificates_verified_but_optional() {
+        assert!(!handshake(tls_config(None).unwrap(), false));
+        assert!(!handshake(tls_config(Some("ca.pem")).unwrap(), false));
+        assert!(handshake(tls_config(Some("ca.pem")).unwrap(), true));
+        assert_eq!(tls_config(None).unwrap().alpn_protocols, [b"h2".to_vec(), b"http/1.1".to_vec()]);
+    }
+
+    #[test]
+    fn invalid_client_ca_refused() {
+        assert!(tls_config(Some("missing.pem")).unwrap_err().starts_with("cannot read"));
+        // a key holds no certificate, leaving no root to verify against
+        assert!(tls_config(Some("client.key")).unwrap_err().starts_with("invalid client CA"));
+    }
+}
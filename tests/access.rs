@@ -0,0 +1,64 @@
+//! Read statistics against a live Redis, given by `TEST_REDIS_URL`; run with `--ignored`
+
+mod common;
+
+use bucket_jai::gql::{create_schema, download_bucket, download_id};
+use bucket_jai::redis::*;
+use juniper::{DefaultScalarValue, ExecutionError, Value, Variables};
+
+use common::{test_context, test_pool};
+
+fn run(ctx: &RedisCtx, query: &str) -> (Value, Vec<ExecutionError<DefaultScalarValue>>) {
+    juniper::execute_sync(query, None, &create_schema(), &Variables::new(), ctx).unwrap()
+}
+
+fn reads(ctx: &RedisCtx, slang: &str) -> String {
+    let (meta, errors) = run(ctx, &format!(r#"{{ bucketMeta(slang: "{}") {{ readCount }} }}"#, slang));
+    assert!(errors.is_empty());
+    meta.to_string()
+}
+
+// slang and id of a new bucket of `data`
+fn create(ctx: &RedisCtx, data: &str) -> (String, String) {
+    let (created, errors) = run(ctx, &format!(r#"mutation {{ createBucket(newBucket: {{ data: "{}", mime: "text/plain" }}) {{ id slang }} }}"#, data));
+    assert!(errors.is_empty());
+    let bucket = created.as_object_value().unwrap().get_field_value("createBucket").unwrap().as_object_value().unwrap();
+    let text = |x: &Value| x.as_scalar_value::<String>().unwrap().to_owned();
+    let slang = &bucket.get_field_value("slang").unwrap().as_list_value().unwrap()[0];
+    (text(slang), text(bucket.get_field_value("id").unwrap()))
+}
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn reads_counted_on_context_and_downloads() {
+    let (pool, keys) = test_pool("access");
+    let ctx = test_context(&pool, &keys, "reader", Quota::default());
+    let (slang, id) = create(&ctx, "read me");
+    assert_eq!(reads(&ctx, &slang), r#"{"bucketMeta": {"readCount": 0}}"#);
+
+    let (_, errors) = run(&ctx, &format!(r#"{{ bucketContext(slang: "{}") {{ data }} }}"#, slang));
+    assert!(errors.is_empty());
+    assert_eq!(reads(&ctx, &slang), r#"{"bucketMeta": {"readCount": 1}}"#);
+
+    assert!(download_bucket(&ctx, slang.to_owned()).is_ok());
+    assert!(download_id(&ctx, id.to_owned()).is_ok());
+    assert_eq!(reads(&ctx, &slang), r#"{"bucketMeta": {"readCount": 3}}"#);
+    let accessed = get_hash_kfv(&pool, &hash_k_id(&keys, &id), &HASH_KF_ACCESSED_AT.to_string()).unwrap();
+    assert!(matches!(accessed, Some(RedisRtn::Payload(_))));
+}
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn reads_after_delete_do_not_recreate_the_bucket() {
+    let (pool, keys) = test_pool("access-deleted");
+    let ctx = test_context(&pool, &keys, "reader", Quota::default());
+    let (_, id) = create(&ctx, "gone soon");
+    let (_, errors) = run(&ctx, &format!(r#"mutation {{ deleteBucket(id: "{}") {{ bucketMeta {{ id }} }} }}"#, id));
+    assert!(errors.is_empty());
+
+    // a read served before the delete reached the replica
+    let key = hash_k_id(&keys, &id);
+    let counted = incr_hash_xx(&pool, &key, &HASH_KF_READS.to_string(), 1, &HASH_KF_ACCESSED_AT.to_string(), &"1".to_string()).unwrap();
+    assert_eq!(counted, None);
+    assert!(get_hash_all(&pool, &key).unwrap().is_empty());
+}
@@ -0,0 +1,34 @@
+//! Live Redis for integration tests, given by `TEST_REDIS_URL`.
+//! Tests needing it are ignored by default, `cargo test -- --ignored` runs them and fails without it.
+
+use std::time::Duration;
+
+use bucket_jai::breaker::CircuitBreaker;
+use bucket_jai::config::{RedisConfig, StorageConfig};
+use bucket_jai::hash::SlangConfig;
+use bucket_jai::redis::*;
+
+/// Pool on `TEST_REDIS_URL` with a key space of its own for test `name`
+pub fn test_pool(name: &str) -> (RedisPool, KeySpace) {
+    let address = std::env::var("TEST_REDIS_URL").expect("TEST_REDIS_URL must name a Redis for tests run with --ignored");
+    let cfg = RedisConfig { address, retries: 0, ..RedisConfig::default() };
+    let client = redis_client(&cfg).expect("invalid TEST_REDIS_URL");
+    let pool = redis_pool(client, 2, Duration::from_secs(5), CircuitBreaker::new(name, 0, Duration::ZERO));
+    let keys = KeySpace { prefix: format!("test-{}-{}:", name, std::process::id()), namespace: DEFAULT_NAMESPACE.to_string() };
+    (pool, keys)
+}
+
+/// Request context of `owner` on `pool`, reading from the primary as well
+#[allow(dead_code)]
+pub fn test_context(pool: &RedisPool, keys: &KeySpace, owner: &str, quota: Quota) -> RedisCtx {
+    RedisCtx {
+        client: pool.clone(),
+        reader: pool.clone(),
+        keys: keys.clone(),
+        owner: owner.to_string(),
+        quota,
+        slang: SlangConfig::default(),
+        storage: StorageConfig::default(),
+        admin: false
+    }
+}
@@ -0,0 +1,59 @@
+//! Bucket creation against a live Redis, given by `TEST_REDIS_URL`; run with `--ignored`
+
+mod common;
+
+use bucket_jai::chunk::{upload_tag, ChunkWriter};
+use bucket_jai::gql::{upload_bucket, write_upload};
+use bucket_jai::hash::get_id_from_context;
+use bucket_jai::redis::*;
+
+use common::{test_context, test_pool};
+
+fn upload(ctx: &RedisCtx, data: &[u8]) -> juniper::FieldResult<String> {
+    let mut writer = ChunkWriter::new(&ctx.client, &ctx.keys, &upload_tag(), 4);
+    write_upload(ctx, &mut writer, data)?;
+    upload_bucket(ctx, writer, Some("text/plain".to_string()), data)?;
+    Ok(get_id_from_context(std::str::from_utf8(data).unwrap()))
+}
+
+fn usage(ctx: &RedisCtx, field: &str) -> Option<String> {
+    match get_hash_kfv(&ctx.client, &hash_k_owner(&ctx.keys, &ctx.owner), &field.to_string()).unwrap() {
+        Some(RedisRtn::Payload(x)) => Some(x),
+        _ => None
+    }
+}
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn concurrent_creates_charge_new_content_once() {
+    let (pool, keys) = test_pool("charge");
+    let ctx = test_context(&pool, &keys, "creator", Quota::default());
+    let ids: Vec<String> = std::thread::scope(|s| {
+        let handles: Vec<_> = (0..4).map(|_| s.spawn(|| upload(&ctx, b"same content").unwrap())).collect();
+        handles.into_iter().map(|x| x.join().unwrap()).collect()
+    });
+    assert!(ids.iter().all(|x| x == &ids[0]));
+    assert_eq!(usage(&ctx, HASH_KF_BUCKETS).as_deref(), Some("1"));
+    assert_eq!(usage(&ctx, HASH_KF_BYTES).as_deref(), Some("12"));
+}
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn create_past_quota_releases_its_claim() {
+    let (pool, keys) = test_pool("release");
+    let small = test_context(&pool, &keys, "small", Quota { bytes: Some(10), buckets: None });
+    let mut writer = ChunkWriter::new(&small.client, &small.keys, &upload_tag(), 4);
+    write_upload(&small, &mut writer, b"01234567").unwrap();
+    // another upload takes the room left while the first one is in flight
+    upload(&small, b"89ab").unwrap();
+    let refused = upload_bucket(&small, writer, None, b"01234567").err().unwrap();
+    assert_eq!(refused.message(), "QUOTA_EXCEEDED");
+    assert_eq!(usage(&small, HASH_KF_BUCKETS).as_deref(), Some("1"));
+
+    // the content is still new to the next owner, who is charged for it
+    let other = test_context(&pool, &keys, "other", Quota::default());
+    let id = upload(&other, b"01234567").unwrap();
+    let owner = get_hash_kfv(&other.client, &hash_k_id(&other.keys, &id), &HASH_KF_OWNER.to_string()).unwrap();
+    assert!(matches!(owner, Some(RedisRtn::Payload(x)) if x == "other"));
+    assert_eq!(usage(&other, HASH_KF_BYTES).as_deref(), Some("8"));
+}
@@ -0,0 +1,172 @@
+//! Consistency repair against a live Redis, given by `TEST_REDIS_URL`; run with `--ignored`
+
+mod common;
+
+use bucket_jai::chunk::{del_chunks, now_millis, set_manifest, upload_tag, ChunkWriter};
+use bucket_jai::fsck::{self, DANGLING_SLANG, HASH_WITHOUT_SLANG, ORPHAN_CHUNK, ORPHAN_HASH, ORPHAN_ZSET, STALE_UPLOAD, STALE_ZSET_MEMBER, UNINDEXED_SLANG, UPLOAD_GRACE};
+use bucket_jai::hash::{get_id_from_context, get_slang_from_id, SlangConfig};
+use bucket_jai::redis::*;
+use redis::Commands;
+
+use common::test_pool;
+
+fn count(report: &fsck::Report, category: &str) -> usize {
+    report.issues.iter().find(|x| x.category == category).map_or(0, |x| x.count)
+}
+
+fn chunk_exists(pool: &RedisPool, keys: &KeySpace, tag: &String, n: usize) -> bool {
+    get_bytes(pool, &str_k_chunk(keys, tag, n)).unwrap().is_some()
+}
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn repair_keeps_chunks_of_uploads_in_flight() {
+    let (pool, keys) = test_pool("inflight");
+    let tag = upload_tag();
+    let mut writer = ChunkWriter::new(&pool, &keys, &tag, 4);
+    writer.write(b"0123456789").unwrap();
+
+    let report = fsck::run(&pool, &keys, &SlangConfig::default(), true).unwrap();
+    assert_eq!(count(&report, ORPHAN_CHUNK), 0);
+    assert!(chunk_exists(&pool, &keys, &tag, 0) && chunk_exists(&pool, &keys, &tag, 1));
+
+    // committing takes the tag out of flight, the manifest then keeping the chunks
+    writer.write(b"ab").unwrap();
+    let (id, manifest) = writer.finish().unwrap();
+    set_manifest(&pool, &keys, &id, &manifest).unwrap();
+    let report = fsck::run(&pool, &keys, &SlangConfig::default(), true).unwrap();
+    assert_eq!(count(&report, ORPHAN_CHUNK), 0);
+    assert!((0..manifest.chunks).all(|n| chunk_exists(&pool, &keys, &tag, n)));
+    assert!(get_sorted_scores(&pool, &zset_k_uploads(&keys)).unwrap().is_empty());
+
+    del_chunks(&pool, &keys, &manifest).unwrap();
+    del_kv(&pool, &hash_k_id(&keys, &id)).unwrap();
+}
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn repair_drops_chunks_of_dead_uploads() {
+    let (pool, keys) = test_pool("dead");
+    let tag = upload_tag();
+    let mut writer = ChunkWriter::new(&pool, &keys, &tag, 4);
+    writer.write(b"01234567").unwrap();
+    // last chunk written before the grace period
+    let stale = now_millis() - UPLOAD_GRACE.as_millis() - 1000;
+    set_sorted_kvs(&pool, &zset_k_uploads(&keys), &tag, stale as isize).unwrap();
+
+    let report = fsck::run(&pool, &keys, &SlangConfig::default(), true).unwrap();
+    assert_eq!(count(&report, ORPHAN_CHUNK), 2);
+    assert_eq!(count(&report, STALE_UPLOAD), 1);
+    assert!(!chunk_exists(&pool, &keys, &tag, 0) && !chunk_exists(&pool, &keys, &tag, 1));
+    assert!(get_sorted_scores(&pool, &zset_k_uploads(&keys)).unwrap().is_empty());
+}
+
+// bucket of `data` reachable by `slang`, as createBucket stores it
+fn bucket(pool: &RedisPool, keys: &KeySpace, data: &str, slang: &str) -> String {
+    let id = get_id_from_context(data);
+    set_hash_kfv(pool, &hash_k_id(keys, &id), &HASH_KF_MIME.to_string(), &"text/plain".to_string()).unwrap();
+    set_hash_kfv(pool, &hash_k_id(keys, &id), &HASH_KF_DATA.to_string(), &data.to_string()).unwrap();
+    set_kv(pool, &str_k_slang(keys, &slang.to_string()), &id).unwrap();
+    set_sorted_kvs(pool, &zset_k_id(keys, &id), &slang.to_string(), 0).unwrap();
+    id
+}
+
+fn exists(pool: &RedisPool, key: &String) -> bool {
+    get_connection(pool).unwrap().exists(key).unwrap()
+}
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn check_reports_without_repairing() {
+    let (pool, keys) = test_pool("check");
+    let id = bucket(&pool, &keys, "deleted", "gone");
+    del_kv(&pool, &hash_k_id(&keys, &id)).unwrap();
+
+    let report = fsck::run(&pool, &keys, &SlangConfig::default(), false).unwrap();
+    assert_eq!(count(&report, DANGLING_SLANG), 1);
+    assert_eq!(report.repaired, 0);
+    assert!(exists(&pool, &str_k_slang(&keys, &"gone".to_string())));
+    del_kv(&pool, &str_k_slang(&keys, &"gone".to_string())).unwrap();
+    del_kv(&pool, &zset_k_id(&keys, &id)).unwrap();
+}
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn repair_drops_slangs_and_zsets_of_missing_buckets() {
+    let (pool, keys) = test_pool("dangling");
+    // slang and zset left behind by an interrupted delete
+    let dangling = bucket(&pool, &keys, "deleted", "gone");
+    del_kv(&pool, &hash_k_id(&keys, &dangling)).unwrap();
+    // zset whose slang now points to another bucket
+    let orphan = get_id_from_context("orphan");
+    let live = bucket(&pool, &keys, "live", "taken");
+    set_sorted_kvs(&pool, &zset_k_id(&keys, &orphan), &"taken".to_string(), 0).unwrap();
+
+    let report = fsck::run(&pool, &keys, &SlangConfig::default(), true).unwrap();
+    assert_eq!(count(&report, DANGLING_SLANG), 1);
+    assert_eq!(count(&report, ORPHAN_ZSET), 1);
+    assert!(!exists(&pool, &str_k_slang(&keys, &"gone".to_string())));
+    assert!(!exists(&pool, &zset_k_id(&keys, &dangling)));
+    assert!(!exists(&pool, &zset_k_id(&keys, &orphan)));
+    // the slang taken by the live bucket is kept
+    assert!(matches!(get_kv(&pool, &str_k_slang(&keys, &"taken".to_string())).unwrap(), Some(RedisRtn::Payload(x)) if x == live));
+
+    let report = fsck::run(&pool, &keys, &SlangConfig::default(), true).unwrap();
+    assert!(report.issues.is_empty());
+}
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn repair_reindexes_slangs() {
+    let (pool, keys) = test_pool("reindex");
+    let id = bucket(&pool, &keys, "indexed", "first");
+    set_kv(&pool, &str_k_slang(&keys, &"second".to_string()), &id).unwrap();
+    // member whose slang was moved to another bucket
+    set_sorted_kvs(&pool, &zset_k_id(&keys, &id), &"moved".to_string(), 0).unwrap();
+
+    let report = fsck::run(&pool, &keys, &SlangConfig::default(), true).unwrap();
+    assert_eq!(count(&report, UNINDEXED_SLANG), 1);
+    assert_eq!(count(&report, STALE_ZSET_MEMBER), 1);
+    let slangs: Vec<String> = get_sorted_scores(&pool, &zset_k_id(&keys, &id)).unwrap().into_iter().map(|x| x.0).collect();
+    assert_eq!(slangs, ["first", "second"]);
+}
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn repair_gives_unreachable_buckets_their_default_slang() {
+    let (pool, keys) = test_pool("unreachable");
+    let slang = SlangConfig::default();
+    let id = bucket(&pool, &keys, "unreachable", "lost");
+    del_kv(&pool, &str_k_slang(&keys, &"lost".to_string())).unwrap();
+    del_kv(&pool, &zset_k_id(&keys, &id)).unwrap();
+
+    let report = fsck::run(&pool, &keys, &slang, true).unwrap();
+    assert_eq!(count(&report, HASH_WITHOUT_SLANG), 1);
+    let default = get_slang_from_id(&id, &slang);
+    assert!(matches!(get_kv(&pool, &str_k_slang(&keys, &default)).unwrap(), Some(RedisRtn::Payload(x)) if x == id));
+    assert_eq!(get_sorted_scores(&pool, &zset_k_id(&keys, &id)).unwrap()[0].0, default);
+    // with its creation time, as slangStats reports
+    let created = get_sorted_scores(&pool, &zset_k_created(&keys, &id)).unwrap();
+    assert!(created[0].0 == default && created[0].1 > 0.0);
+}
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn repair_drops_hashes_without_content() {
+    let (pool, keys) = test_pool("orphan-hash");
+    // reads counted after a delete
+    let id = get_id_from_context("deleted");
+    set_hash_kfv(&pool, &hash_k_id(&keys, &id), &HASH_KF_READS.to_string(), &"3".to_string()).unwrap();
+    set_hash_kfv(&pool, &hash_k_id(&keys, &id), &HASH_KF_ACCESSED_AT.to_string(), &"1".to_string()).unwrap();
+    // keys not named by a bucket id are not given a slang
+    let odd = "é".to_string();
+    set_hash_kfv(&pool, &hash_k_id(&keys, &odd), &HASH_KF_MIME.to_string(), &"text/plain".to_string()).unwrap();
+
+    let report = fsck::run(&pool, &keys, &SlangConfig::default(), true).unwrap();
+    assert_eq!(count(&report, ORPHAN_HASH), 1);
+    assert_eq!(count(&report, HASH_WITHOUT_SLANG), 0);
+    assert!(!exists(&pool, &hash_k_id(&keys, &id)));
+    assert!(!exists(&pool, &zset_k_id(&keys, &id)));
+    assert!(exists(&pool, &hash_k_id(&keys, &odd)));
+    del_kv(&pool, &hash_k_id(&keys, &odd)).unwrap();
+}
@@ -0,0 +1,31 @@
+//! Readiness against a live Redis, given by `TEST_REDIS_URL`; run with `--ignored`
+
+mod common;
+
+use std::time::Duration;
+
+use bucket_jai::health::readiness;
+use bucket_jai::redis::*;
+
+use common::test_pool;
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn ready_while_every_connection_is_briefly_busy() {
+    let (pool, keys) = test_pool("health");
+    set_schema_version(&pool, &keys, SCHEMA_VERSION).unwrap();
+    let (ready, body) = readiness(&pool, None, &keys, false);
+    assert!(ready, "{}", body);
+
+    // peak load takes every connection, one coming back before the probe times out
+    let busy: Vec<_> = (0..pool.max_size()).map(|_| get_connection(&pool).unwrap()).collect();
+    let (ready, body) = std::thread::scope(|s| {
+        s.spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            drop(busy);
+        });
+        readiness(&pool, None, &keys, false)
+    });
+    assert!(ready, "{}", body);
+    del_kv(&pool, &str_k_schema(&keys)).unwrap();
+}
@@ -0,0 +1,106 @@
+//! Bucket labels against a live Redis, given by `TEST_REDIS_URL`; run with `--ignored`
+
+mod common;
+
+use bucket_jai::fsck::{self, ORPHAN_LABELS, STALE_LABEL};
+use bucket_jai::gql::create_schema;
+use bucket_jai::hash::{get_id_from_context, SlangConfig};
+use bucket_jai::redis::*;
+use juniper::{DefaultScalarValue, ExecutionError, Value, Variables};
+
+use common::{test_context, test_pool};
+
+fn run(ctx: &RedisCtx, query: &str) -> (Value, Vec<ExecutionError<DefaultScalarValue>>) {
+    juniper::execute_sync(query, None, &create_schema(), &Variables::new(), ctx).unwrap()
+}
+
+fn ok(ctx: &RedisCtx, query: &str) {
+    let (_, errors) = run(ctx, query);
+    assert!(errors.is_empty(), "{:?}", errors);
+}
+
+// ids of the buckets listed by bucketsByLabel
+fn labelled(ctx: &RedisCtx, key: &str, value: &str) -> String {
+    let (found, errors) = run(ctx, &format!(r#"{{ bucketsByLabel(key: "{}", value: "{}") {{ id }} }}"#, key, value));
+    assert!(errors.is_empty());
+    found.to_string()
+}
+
+fn ids(ids: &[&String]) -> String {
+    let list: Vec<String> = ids.iter().map(|x| format!(r#"{{"id": "{}"}}"#, x)).collect();
+    format!(r#"{{"bucketsByLabel": [{}]}}"#, list.join(", "))
+}
+
+fn indexed(ctx: &RedisCtx, pair: &str) -> Vec<String> {
+    match get_set_kv(&ctx.client, &set_k_label(&ctx.keys, &pair.to_string())).unwrap() {
+        Some(RedisRtn::Payloads(x)) => x,
+        _ => Vec::new()
+    }
+}
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn labels_indexed_through_set_overwrite_remove_and_delete() {
+    let (pool, keys) = test_pool("labels");
+    let ctx = test_context(&pool, &keys, "labeller", Quota::default());
+    let (a, b) = (get_id_from_context("first"), get_id_from_context("second"));
+    ok(&ctx, r#"mutation { createBucket(newBucket: { data: "first", mime: "text/plain", labels: [{ key: "env", value: "prod" }, { key: "team", value: "core" }] }) { id } }"#);
+    ok(&ctx, r#"mutation { createBucket(newBucket: { data: "second", mime: "text/plain", labels: [{ key: "env", value: "prod" }] }) { id } }"#);
+    let mut both = [&a, &b];
+    both.sort();
+    assert_eq!(labelled(&ctx, "env", "prod"), ids(&both));
+    assert_eq!(labelled(&ctx, "team", "core"), ids(&[&a]));
+
+    // an overwrite moves the bucket to the new value
+    ok(&ctx, &format!(r#"mutation {{ setLabels(id: "{}", labels: [{{ key: "env", value: "dev" }}]) {{ id }} }}"#, a));
+    assert_eq!(labelled(&ctx, "env", "prod"), ids(&[&b]));
+    assert_eq!(labelled(&ctx, "env", "dev"), ids(&[&a]));
+    assert_eq!(indexed(&ctx, "env=prod"), [b.to_owned()]);
+
+    ok(&ctx, &format!(r#"mutation {{ removeLabels(id: "{}", keys: ["team", "missing"]) {{ id }} }}"#, a));
+    assert_eq!(labelled(&ctx, "team", "core"), ids(&[]));
+    assert!(indexed(&ctx, "team=core").is_empty());
+    assert!(get_hash_kfv(&pool, &hash_k_labels(&keys, &a), &"team".to_string()).unwrap().is_none());
+
+    ok(&ctx, &format!(r#"mutation {{ deleteBucket(id: "{}") {{ bucketMeta {{ id }} }} }}"#, b));
+    assert_eq!(labelled(&ctx, "env", "prod"), ids(&[]));
+    assert!(indexed(&ctx, "env=prod").is_empty());
+    assert!(get_hash_all(&pool, &hash_k_labels(&keys, &b)).unwrap().is_empty());
+}
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn invalid_labels_refused() {
+    let (pool, keys) = test_pool("labels-invalid");
+    let ctx = test_context(&pool, &keys, "labeller", Quota::default());
+    ok(&ctx, r#"mutation { createBucket(newBucket: { data: "labelled", mime: "text/plain" }) { id } }"#);
+    let id = get_id_from_context("labelled");
+
+    let (_, errors) = run(&ctx, &format!(r#"mutation {{ setLabels(id: "{}", labels: [{{ key: "a=b", value: "c" }}]) {{ id }} }}"#, id));
+    assert_eq!(errors[0].error().message(), "INVALID_LABEL");
+    let (_, errors) = run(&ctx, r#"{ bucketsByLabel(key: "a=b", value: "c") { id } }"#);
+    assert_eq!(errors[0].error().message(), "INVALID_LABEL");
+    assert!(get_hash_all(&pool, &hash_k_labels(&keys, &id)).unwrap().is_empty());
+}
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn repair_drops_stale_and_orphaned_labels() {
+    let (pool, keys) = test_pool("labels-fsck");
+    let ctx = test_context(&pool, &keys, "labeller", Quota::default());
+    ok(&ctx, r#"mutation { createBucket(newBucket: { data: "kept", mime: "text/plain", labels: [{ key: "env", value: "prod" }] }) { id } }"#);
+    let kept = get_id_from_context("kept");
+    // entry of a value since overwritten, and labels of a bucket gone
+    set_set_kvs(&pool, &set_k_label(&keys, &"env=dev".to_string()), &kept).unwrap();
+    let gone = get_id_from_context("gone");
+    set_hash_kfv(&pool, &hash_k_labels(&keys, &gone), &"env".to_string(), &"prod".to_string()).unwrap();
+    set_set_kvs(&pool, &set_k_label(&keys, &"env=prod".to_string()), &gone).unwrap();
+
+    let report = fsck::run(&pool, &keys, &SlangConfig::default(), true).unwrap();
+    let count = |category: &str| report.issues.iter().find(|x| x.category == category).map_or(0, |x| x.count);
+    assert_eq!(count(STALE_LABEL), 2);
+    assert_eq!(count(ORPHAN_LABELS), 1);
+    assert!(indexed(&ctx, "env=dev").is_empty());
+    assert_eq!(indexed(&ctx, "env=prod"), [kept.to_owned()]);
+    assert_eq!(labelled(&ctx, "env", "prod"), ids(&[&kept]));
+}
@@ -0,0 +1,79 @@
+//! Key schema migrations against a live Redis, given by `TEST_REDIS_URL`; run with `--ignored`
+
+mod common;
+
+use bucket_jai::migrate::{self, Options};
+use bucket_jai::redis::*;
+
+use common::test_pool;
+
+fn field(pool: &RedisPool, key: &String, field: &str) -> Option<String> {
+    match get_hash_kfv(pool, key, &field.to_string()).unwrap() {
+        Some(RedisRtn::Payload(x)) => Some(x),
+        _ => None
+    }
+}
+
+// a bucket of `data` in `namespace` as stored before schema version 2
+fn legacy_bucket(pool: &RedisPool, keys: &KeySpace, namespace: &str, id: &str, data: &str) {
+    let head = match namespace { DEFAULT_NAMESPACE => keys.prefix.to_owned(), x => format!("{}ns:{}:", keys.prefix, x) };
+    set_hash_kfv(pool, &format!("{}bkt:hash:id:{}", head, id), &HASH_KF_DATA.to_string(), &data.to_string()).unwrap();
+    set_hash_kfv(pool, &format!("{}bkt:hash:id:{}", head, id), &HASH_KF_MIME.to_string(), &"text/plain".to_string()).unwrap();
+    set_sorted_kvs(pool, &format!("{}slgs:zset:id:{}", head, id), &format!("slang-{}", id), 0).unwrap();
+}
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn legacy_buckets_backfilled_and_tagged() {
+    let (pool, keys) = test_pool("migrate");
+    legacy_bucket(&pool, &keys, DEFAULT_NAMESPACE, "abc", "hello");
+    legacy_bucket(&pool, &keys, "team", "def", "hi");
+    set_schema_version(&pool, &keys, SCHEMA_VERSION_LEGACY).unwrap();
+
+    let version = migrate::run(&pool, &keys, &Options { dry_run: false, batch: 1 }).unwrap();
+    assert_eq!(version, SCHEMA_VERSION);
+    assert_eq!(get_schema_version(&pool, &keys).unwrap(), Some(SCHEMA_VERSION));
+
+    let team = KeySpace { prefix: keys.prefix.to_owned(), namespace: "team".to_string() };
+    for (ks, id, size) in [(&keys, "abc", "5"), (&team, "def", "2")] {
+        let id = id.to_string();
+        assert_eq!(field(&pool, &hash_k_id(ks, &id), HASH_KF_SIZE).as_deref(), Some(size));
+        assert!(field(&pool, &hash_k_id(ks, &id), HASH_KF_DATA).is_some());
+        assert_eq!(get_sorted_scores(&pool, &zset_k_id(ks, &id)).unwrap().len(), 1);
+        assert_eq!(field(&pool, &hash_k_namespace(ks, &ks.namespace), HASH_KF_BYTES).as_deref(), Some(size));
+    }
+    assert!(field(&pool, &format!("{}bkt:hash:id:abc", keys.prefix), HASH_KF_DATA).is_none());
+    assert!(get_sorted_scores(&pool, &format!("{}slgs:zset:id:abc", keys.prefix)).unwrap().is_empty());
+
+    // a rerun finds nothing left to migrate, and does not count usage twice
+    assert_eq!(migrate::run(&pool, &keys, &Options { dry_run: false, batch: 10 }).unwrap(), SCHEMA_VERSION);
+    assert_eq!(field(&pool, &hash_k_namespace(&keys, &keys.namespace), HASH_KF_BUCKETS).as_deref(), Some("1"));
+}
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn dry_run_changes_nothing() {
+    let (pool, keys) = test_pool("migrate-dry");
+    legacy_bucket(&pool, &keys, DEFAULT_NAMESPACE, "abc", "hello");
+    set_schema_version(&pool, &keys, SCHEMA_VERSION_LEGACY).unwrap();
+
+    assert_eq!(migrate::run(&pool, &keys, &Options { dry_run: true, batch: 10 }).unwrap(), SCHEMA_VERSION_LEGACY);
+    assert_eq!(get_schema_version(&pool, &keys).unwrap(), Some(SCHEMA_VERSION_LEGACY));
+    let legacy = format!("{}bkt:hash:id:abc", keys.prefix);
+    assert!(field(&pool, &legacy, HASH_KF_DATA).is_some() && field(&pool, &legacy, HASH_KF_SIZE).is_none());
+}
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn tagged_keys_left_as_they_are() {
+    let (pool, keys) = test_pool("migrate-tagged");
+    let id = "abc".to_string();
+    set_hash_kfv(&pool, &hash_k_id(&keys, &id), &HASH_KF_DATA.to_string(), &"hello".to_string()).unwrap();
+    set_hash_kfv(&pool, &hash_k_id(&keys, &id), &HASH_KF_SIZE.to_string(), &"5".to_string()).unwrap();
+    set_schema_version(&pool, &keys, 2).unwrap();
+
+    assert_eq!(migrate::run(&pool, &keys, &Options { dry_run: false, batch: 10 }).unwrap(), SCHEMA_VERSION);
+    assert_eq!(field(&pool, &hash_k_id(&keys, &id), HASH_KF_DATA).as_deref(), Some("hello"));
+    // the usage backfill of version 2 is not applied again
+    assert!(field(&pool, &hash_k_namespace(&keys, &keys.namespace), HASH_KF_BYTES).is_none());
+}
@@ -0,0 +1,33 @@
+//! Schema version stamping against a live Redis, given by `TEST_REDIS_URL`; run with `--ignored`
+
+mod common;
+
+use bucket_jai::redis::*;
+
+use common::test_pool;
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn keys_of_other_apps_are_not_legacy_data() {
+    let (pool, keys) = test_pool("schema-shared");
+    let other = format!("{}sessions:42", keys.prefix);
+    set_kv(&pool, &other, &"x".to_string()).unwrap();
+
+    assert_eq!(check_schema_version(&pool, &keys), Ok(SCHEMA_VERSION));
+    assert_eq!(get_schema_version(&pool, &keys).unwrap(), Some(SCHEMA_VERSION));
+    del_kv(&pool, &other).unwrap();
+    del_kv(&pool, &str_k_schema(&keys)).unwrap();
+}
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn unstamped_buckets_are_legacy_data() {
+    let (pool, keys) = test_pool("schema-legacy");
+    let bucket = format!("{}bkt:hash:id:abc", keys.prefix);
+    set_hash_kfv(&pool, &bucket, &HASH_KF_MIME.to_string(), &"text/plain".to_string()).unwrap();
+
+    assert!(check_schema_version(&pool, &keys).is_err());
+    assert_eq!(get_schema_version(&pool, &keys).unwrap(), Some(SCHEMA_VERSION_LEGACY));
+    del_kv(&pool, &bucket).unwrap();
+    del_kv(&pool, &str_k_schema(&keys)).unwrap();
+}
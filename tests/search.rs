@@ -0,0 +1,38 @@
+//! Full-text search against a live Redis, given by `TEST_REDIS_URL`; run with `--ignored`
+
+mod common;
+
+use bucket_jai::gql::create_schema;
+use bucket_jai::redis::*;
+use juniper::{DefaultScalarValue, ExecutionError, Value, Variables};
+
+use common::{test_context, test_pool};
+
+fn run(ctx: &RedisCtx, query: &str) -> (Value, Vec<ExecutionError<DefaultScalarValue>>) {
+    juniper::execute_sync(query, None, &create_schema(), &Variables::new(), ctx).unwrap()
+}
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn created_buckets_found_by_every_term() {
+    let (pool, keys) = test_pool("search");
+    let ctx = test_context(&pool, &keys, "searcher", Quota::default());
+    let (_, errors) = run(&ctx, r#"mutation { createBucket(newBucket: { data: "red green blue", mime: "text/plain" }) { id } }"#);
+    assert!(errors.is_empty());
+
+    let (found, _) = run(&ctx, r#"{ search(query: "Blue red") { hits { snippet } } }"#);
+    assert_eq!(found.to_string(), r#"{"search": {"hits": [{"snippet": "red green blue"}]}}"#);
+    let (missed, _) = run(&ctx, r#"{ search(query: "red yellow") { hits { snippet } } }"#);
+    assert_eq!(missed.to_string(), r#"{"search": {"hits": []}}"#);
+}
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn queries_of_too_many_terms_refused() {
+    let (pool, keys) = test_pool("search-terms");
+    let ctx = test_context(&pool, &keys, "searcher", Quota::default());
+    let (_, errors) = run(&ctx, r#"{ search(query: "a b c d e f g h i") { hits { snippet } } }"#);
+    assert_eq!(errors[0].error().message(), "TOO_MANY_TERMS");
+    let (_, errors) = run(&ctx, r#"{ search(query: "a b c d e f g h h") { hits { snippet } } }"#);
+    assert!(errors.is_empty());
+}
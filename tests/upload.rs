@@ -0,0 +1,28 @@
+//! Streamed uploads against a live Redis, given by `TEST_REDIS_URL`; run with `--ignored`
+
+mod common;
+
+use bucket_jai::chunk::{upload_tag, ChunkWriter};
+use bucket_jai::gql::{check_quota, write_upload};
+use bucket_jai::redis::*;
+
+use common::{test_context, test_pool};
+
+#[test]
+#[ignore = "needs TEST_REDIS_URL"]
+fn upload_stops_before_chunks_past_quota() {
+    let (pool, keys) = test_pool("quota");
+    let ctx = test_context(&pool, &keys, "uploader", Quota { bytes: Some(10), buckets: None });
+    assert!(check_quota(&ctx, 10).is_ok());
+    assert_eq!(check_quota(&ctx, 11).unwrap_err().message(), "QUOTA_EXCEEDED");
+
+    let tag = upload_tag();
+    let mut writer = ChunkWriter::new(&ctx.client, &ctx.keys, &tag, 4);
+    write_upload(&ctx, &mut writer, b"01234567").unwrap();
+    assert_eq!(write_upload(&ctx, &mut writer, b"89ab").unwrap_err().message(), "QUOTA_EXCEEDED");
+    // nothing past the quota reached Redis
+    assert_eq!(writer.size(), 8);
+    assert!(get_bytes(&ctx.client, &str_k_chunk(&ctx.keys, &tag, 2)).unwrap().is_none());
+    writer.abort();
+    assert!(get_bytes(&ctx.client, &str_k_chunk(&ctx.keys, &tag, 0)).unwrap().is_none());
+}